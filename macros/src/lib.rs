@@ -0,0 +1,117 @@
+//! The `#[trace]` attribute and `#[derive(TraceTags)]`, re-exported by tsc-trace.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Fields, ItemFn, LitStr};
+
+/// Traces every call of a function, as if it started with `trace_span!(tag)`.
+///
+/// `#[trace]` tags it with [`name_tag`](https://docs.rs/tsc-trace/latest/tsc_trace/fn.name_tag.html)
+/// of its path, e.g. `my_crate::parse::header`, which stays the same from build to build as long as the path does,
+/// and registers that path as the tag's name with `register_tag` the first time the function is called.
+/// Methods of the same name on different types in one module have the same path, so give them different names
+/// with `#[trace(name = "Parser::new")]`, which is then hashed and registered instead.
+/// `#[trace(tag = Traces::Header)]` uses the given tag as it is, and only registers a name for it if one is given too,
+/// so the tag keeps any name it already has, e.g. from `#[derive(TraceTags)]`.
+///
+/// With tsc-trace's `"off"` feature, the function is left as it is.
+#[proc_macro_attribute]
+pub fn trace(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut tag: Option<Expr> = None;
+    let mut name: Option<LitStr> = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("tag") {
+            tag = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("expected `tag = ...` or `name = \"...\"`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+    let mut function = parse_macro_input!(item as ItemFn);
+    if let Some(asyncness) = function.sig.asyncness {
+        return syn::Error::new_spanned(asyncness, "#[trace] doesn't support async functions")
+            .to_compile_error()
+            .into();
+    }
+    if cfg!(feature = "off") {
+        return quote!(#function).into();
+    }
+
+    let ident = function.sig.ident.to_string();
+    let (tag, name) = match (tag, name) {
+        (Some(tag), name) => (quote!((#tag) as u64), name.map(|name| quote!(#name))),
+        (None, name) => {
+            let name = match name {
+                Some(name) => quote!(#name),
+                None => quote!(concat!(module_path!(), "::", #ident)),
+            };
+            let tag = quote!({
+                const TAG: u64 = ::tsc_trace::name_tag(#name);
+                TAG
+            });
+            (tag, Some(name))
+        }
+    };
+    let register = name.map(|name| {
+        quote!(
+            static _TSC_TRACE_REGISTERED: ::std::sync::Once = ::std::sync::Once::new();
+            ::tsc_trace::_register_tag_once(&_TSC_TRACE_REGISTERED, _tsc_trace_tag, #name);
+        )
+    });
+    let body = &function.block.stmts;
+    function.block = syn::parse_quote!({
+        let _tsc_trace_tag: u64 = #tag;
+        #register
+        let _tsc_trace_span = ::tsc_trace::TraceSpan::new(_tsc_trace_tag);
+        #(#body)*
+    });
+    quote!(#function).into()
+}
+
+/// Implements `TraceTags` for a fieldless enum of tags, naming each tag after its variant,
+/// or as given by `#[trace_tag(name = "...")]` on the variant.
+#[proc_macro_derive(TraceTags, attributes(trace_tag))]
+pub fn derive_trace_tags(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    let Data::Enum(data) = &input.data else {
+        return syn::Error::new_spanned(&input.ident, "TraceTags can only be derived for enums")
+            .to_compile_error()
+            .into();
+    };
+    let mut tags = vec![];
+    for variant in &data.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return syn::Error::new_spanned(variant, "TraceTags variants can't have fields")
+                .to_compile_error()
+                .into();
+        }
+        let mut name = LitStr::new(&variant.ident.to_string(), variant.ident.span());
+        for attr in variant.attrs.iter().filter(|attr| attr.path().is_ident("trace_tag")) {
+            let parsed = attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("name") {
+                    name = meta.value()?.parse()?;
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `name = \"...\"`"))
+                }
+            });
+            if let Err(e) = parsed {
+                return e.to_compile_error().into();
+            }
+        }
+        let ident = &variant.ident;
+        tags.push(quote!((Self::#ident as u64, #name)));
+    }
+    let ident = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    quote!(
+        impl #impl_generics ::tsc_trace::TraceTags for #ident #type_generics #where_clause {
+            const TAGS: &'static [(u64, &'static str)] = &[#(#tags),*];
+        }
+    )
+    .into()
+}
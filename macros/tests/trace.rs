@@ -0,0 +1,87 @@
+#![cfg(not(feature = "off"))]
+
+use std::collections::BTreeMap;
+
+use tsc_trace::{name_tag, trace, traces_in_order, write_trace_file, TraceReader, TraceTags};
+
+fn tag_names() -> BTreeMap<u64, String> {
+    let mut file = vec![];
+    write_trace_file(&mut file).unwrap();
+    TraceReader::new(&file[..]).unwrap().tag_names().clone()
+}
+
+fn recorded(tag: u64) -> bool {
+    traces_in_order().any(|trace| trace.tag == tag)
+}
+
+#[trace]
+fn by_path() {}
+
+#[test]
+fn tags_by_path_and_registers_it() {
+    by_path();
+    let path = concat!(module_path!(), "::by_path");
+    assert!(recorded(name_tag(path)));
+    assert_eq!(
+        tag_names().get(&name_tag(path)).map(String::as_str),
+        Some(path)
+    );
+}
+
+struct Parser;
+
+impl Parser {
+    #[trace(name = "Parser::new")]
+    fn new() -> Self {
+        Parser
+    }
+}
+
+#[test]
+fn tags_by_given_name_and_registers_it() {
+    let _ = Parser::new();
+    assert!(recorded(name_tag("Parser::new")));
+    assert_eq!(
+        tag_names()
+            .get(&name_tag("Parser::new"))
+            .map(String::as_str),
+        Some("Parser::new")
+    );
+}
+
+#[derive(TraceTags)]
+enum Tags {
+    Kept = 3,
+    Renamed = 4,
+}
+
+#[trace(tag = Tags::Kept)]
+fn with_tag() {}
+
+#[trace(tag = Tags::Renamed, name = "renamed")]
+fn with_tag_and_name() {}
+
+#[test]
+fn given_tag_keeps_its_name_unless_one_is_given() {
+    Tags::register_tags();
+    with_tag();
+    with_tag_and_name();
+    assert!(recorded(3) && recorded(4));
+    let names = tag_names();
+    assert_eq!(names.get(&3).map(String::as_str), Some("Kept"));
+    assert_eq!(names.get(&4).map(String::as_str), Some("renamed"));
+}
+
+#[trace]
+fn returns(a: u32, b: u32) -> u32 {
+    if a > b {
+        return a - b;
+    }
+    b - a
+}
+
+#[test]
+fn keeps_the_function_body() {
+    assert_eq!(returns(5, 3), 2);
+    assert_eq!(returns(3, 5), 2);
+}
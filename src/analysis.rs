@@ -0,0 +1,311 @@
+//! Time spent in each tag, with and without the spans nested inside it.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+#[cfg(feature = "nesting")]
+use std::collections::HashMap;
+use std::io::{Read, Result};
+
+use crate::{registry, RecordKind, Trace, TraceReader, LIFETIME_FLAG};
+
+/// Totals for one tag, in ticks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TagCost {
+    /// The tag, without any bits above [`TAG_MASK`](crate::TAG_MASK), as given to `trace_span!`.
+    pub tag: u64,
+    /// Number of traces with this tag.
+    pub calls: u64,
+    /// Time from start to stop, children included.
+    /// A trace nested (directly or not) inside another with the same tag isn't counted again, so recursion doesn't inflate it.
+    pub inclusive: u64,
+    /// Time from start to stop, minus the time of the traces directly nested inside it.
+    pub exclusive: u64,
+    /// Number of lifetime spans of futures traced with this tag and [`with_lifetime`](crate::Traced::with_lifetime).
+    /// They overlap the spans of each poll, so they aren't counted in `calls`, `inclusive` or `exclusive`.
+    pub lifetimes: u64,
+    /// Total time of those lifetime spans, from first poll to completion.
+    pub lifetime: u64,
+}
+
+/// Reports [`TagCost`]s for the current thread's traces, ordered by tag.
+/// See [`all_threads_tag_costs`] for how nesting is worked out.
+pub fn tag_costs() -> Vec<TagCost> {
+    let mut costs = Costs::default();
+    costs.thread(registry::with_current(|buffer| buffer.records().collect()));
+    costs.finish()
+}
+
+/// Reports [`TagCost`]s for the traces of every thread, ordered by tag.
+///
+/// Nesting is rebuilt separately for each thread.
+/// With the `"nesting"` feature, each trace's parent is the one its `parent` field names.
+/// Otherwise (or for traces without span ids, e.g. read from a file written without the feature),
+/// a trace's parent is the innermost earlier trace on the same thread that contains it in time.
+/// A parent missing because the ring wrapped around makes its children top level.
+/// Traces whose stop is before their start, and records other than spans (e.g. flows), are left out.
+/// Lifetime spans of futures are neither parents nor children, and are totalled separately.
+pub fn all_threads_tag_costs() -> Vec<TagCost> {
+    let mut costs = Costs::default();
+    let _ = registry::for_each_thread(|thread| {
+        costs.thread(thread.traces);
+        Ok(())
+    });
+    costs.finish()
+}
+
+/// Reports [`TagCost`]s for traces read from a file, e.g. by [`TraceReader::open`], ordered by tag.
+///
+/// Traces are grouped into threads as they were written, and bare traces are treated as one thread.
+/// Ticks are as recorded; the file header's `ticks_per_ns` converts them to nanoseconds.
+pub fn tag_costs_from<R: Read>(mut reader: TraceReader<R>) -> Result<Vec<TagCost>> {
+    let mut costs = Costs::default();
+    let mut thread_id = None;
+    let mut traces = vec![];
+    while let Some(trace) = reader.next() {
+        let trace = trace?;
+        if thread_id != reader.thread_id() {
+            costs.thread(std::mem::take(&mut traces));
+            thread_id = reader.thread_id();
+        }
+        traces.push(trace);
+    }
+    costs.thread(traces);
+    Ok(costs.finish())
+}
+
+#[derive(Default)]
+struct Costs(BTreeMap<u64, TagCost>);
+
+impl Costs {
+    /// Adds one thread's traces.
+    fn thread(&mut self, mut traces: Vec<Trace>) {
+        traces.retain(|trace| trace.kind() == RecordKind::Span && trace.stop >= trace.start);
+        let (lifetimes, mut traces): (Vec<Trace>, Vec<Trace>) =
+            traces.into_iter().partition(|trace| trace.tag & LIFETIME_FLAG != 0);
+        for lifetime in &lifetimes {
+            let cost = self.cost(lifetime.base_tag());
+            cost.lifetimes += 1;
+            cost.lifetime += duration(lifetime);
+        }
+        let parents = parents(&mut traces);
+        let mut children = vec![0u64; traces.len()];
+        for (trace, parent) in traces.iter().zip(&parents) {
+            if let Some(parent) = *parent {
+                children[parent] += duration(trace);
+            }
+        }
+        for (i, trace) in traces.iter().enumerate() {
+            let cost = self.cost(trace.base_tag());
+            cost.calls += 1;
+            cost.exclusive += duration(trace).saturating_sub(children[i]);
+            let mut ancestor = parents[i];
+            while let Some(a) = ancestor {
+                if traces[a].base_tag() == trace.base_tag() {
+                    break;
+                }
+                ancestor = parents[a];
+            }
+            if ancestor.is_none() {
+                cost.inclusive += duration(trace);
+            }
+        }
+    }
+
+    fn cost(&mut self, tag: u64) -> &mut TagCost {
+        self.0.entry(tag).or_insert(TagCost {
+            tag,
+            ..TagCost::default()
+        })
+    }
+
+    fn finish(self) -> Vec<TagCost> {
+        self.0.into_values().collect()
+    }
+}
+
+fn duration(trace: &Trace) -> u64 {
+    trace.stop - trace.start
+}
+
+/// Index of each trace's parent, if it has one. May reorder `traces`.
+fn parents(traces: &mut [Trace]) -> Vec<Option<usize>> {
+    #[cfg(feature = "nesting")]
+    if traces.iter().all(|trace| trace.id != crate::NO_SPAN) {
+        return parents_by_id(traces);
+    }
+    parents_by_time(traces)
+}
+
+/// Parents named by span id. Parents always have smaller ids than their children,
+/// so anything else (e.g. ids reused after [`clear_thread_traces`](crate::clear_thread_traces)) isn't taken as a parent.
+#[cfg(feature = "nesting")]
+fn parents_by_id(traces: &[Trace]) -> Vec<Option<usize>> {
+    let index: HashMap<u64, usize> = traces
+        .iter()
+        .enumerate()
+        .map(|(i, trace)| (trace.id, i))
+        .collect();
+    traces
+        .iter()
+        .map(|trace| {
+            index
+                .get(&trace.parent)
+                .copied()
+                .filter(|&parent| traces[parent].id < trace.id)
+        })
+        .collect()
+}
+
+/// Parents worked out from start and stop: the innermost still open trace that contains each one.
+fn parents_by_time(traces: &mut [Trace]) -> Vec<Option<usize>> {
+    traces.sort_by_key(|trace| (trace.start, Reverse(trace.stop)));
+    let mut parents = Vec::with_capacity(traces.len());
+    // traces that started earlier and haven't stopped yet, outermost first
+    let mut open: Vec<usize> = vec![];
+    for (i, trace) in traces.iter().enumerate() {
+        open.retain(|&j| traces[j].stop > trace.start);
+        parents.push(open.iter().rev().copied().find(|&j| traces[j].stop >= trace.stop));
+        open.push(i);
+    }
+    parents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn costs(traces: &[Trace]) -> Vec<TagCost> {
+        let mut costs = Costs::default();
+        costs.thread(traces.to_vec());
+        costs.finish()
+    }
+
+    fn cost(tag: u64, calls: u64, inclusive: u64, exclusive: u64) -> TagCost {
+        TagCost {
+            tag,
+            calls,
+            inclusive,
+            exclusive,
+            ..TagCost::default()
+        }
+    }
+
+    #[cfg(feature = "nesting")]
+    fn with_ids(id: u64, parent: u64, mut trace: Trace) -> Trace {
+        trace.id = id;
+        trace.parent = parent;
+        trace
+    }
+
+    #[test]
+    fn exclusive_time_leaves_out_direct_children_only() {
+        let traces = [
+            Trace::new(1, 0, 10),
+            Trace::new(2, 1, 3),
+            Trace::new(3, 4, 7),
+            Trace::new(4, 5, 6),
+        ];
+        assert_eq!(
+            costs(&traces),
+            [cost(1, 1, 10, 5), cost(2, 1, 2, 2), cost(3, 1, 3, 2), cost(4, 1, 1, 1)]
+        );
+    }
+
+    #[test]
+    fn recursive_tag_is_counted_once_in_inclusive_time() {
+        let traces = [Trace::new(1, 0, 10), Trace::new(1, 2, 8), Trace::new(1, 3, 5)];
+        assert_eq!(costs(&traces), [cost(1, 3, 10, 10)]);
+    }
+
+    #[test]
+    fn recursion_through_another_tag_is_counted_once() {
+        let traces = [Trace::new(1, 0, 10), Trace::new(2, 1, 9), Trace::new(1, 2, 4)];
+        assert_eq!(costs(&traces), [cost(1, 2, 10, 4), cost(2, 1, 8, 6)]);
+    }
+
+    #[test]
+    fn reversed_traces_and_other_records_are_left_out() {
+        let traces = [
+            Trace::new(1, 0, 10),
+            Trace::new(2, 5, 3),
+            Trace::new(3 | RecordKind::Instant.tag_bits(), 4, 0),
+            Trace::new(3 | RecordKind::Counter.tag_bits(), 4, 100),
+            Trace::new(RecordKind::FlowStart.tag_bits(), 2, 7),
+            Trace::new(RecordKind::FlowStep.tag_bits(), 6, 7),
+        ];
+        assert_eq!(costs(&traces), [cost(1, 1, 10, 10)]);
+    }
+
+    #[test]
+    fn lifetimes_are_totalled_apart_from_polls() {
+        let traces = [
+            Trace::new(1 | LIFETIME_FLAG, 0, 20),
+            Trace::new(1, 0, 5),
+            Trace::new(2, 1, 2),
+            Trace::new(1, 10, 15),
+        ];
+        let polls = TagCost {
+            lifetimes: 1,
+            lifetime: 20,
+            ..cost(1, 2, 10, 9)
+        };
+        assert_eq!(costs(&traces), [polls, cost(2, 1, 1, 1)]);
+    }
+
+    #[test]
+    fn equal_timestamps_nest_only_when_contained() {
+        // back to back, so siblings
+        assert_eq!(
+            costs(&[Trace::new(1, 0, 5), Trace::new(2, 5, 10)]),
+            [cost(1, 1, 5, 5), cost(2, 1, 5, 5)]
+        );
+        // the same start and stop, so the earlier one is the parent
+        assert_eq!(
+            costs(&[Trace::new(1, 0, 5), Trace::new(2, 0, 5)]),
+            [cost(1, 1, 5, 0), cost(2, 1, 5, 5)]
+        );
+        // an empty span where another stops is after it
+        assert_eq!(
+            costs(&[Trace::new(1, 0, 5), Trace::new(2, 5, 5)]),
+            [cost(1, 1, 5, 5), cost(2, 1, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn parents_by_time_are_innermost_containing_traces() {
+        let mut traces = [
+            Trace::new(2, 1, 4),
+            Trace::new(1, 0, 10),
+            Trace::new(3, 2, 3),
+            Trace::new(4, 5, 12),
+        ];
+        let parents = parents_by_time(&mut traces);
+        let tags: Vec<u64> = traces.iter().map(|trace| trace.tag).collect();
+        assert_eq!(tags, [1, 2, 3, 4]);
+        assert_eq!(parents, [None, Some(0), Some(1), None]);
+    }
+
+    #[cfg(feature = "nesting")]
+    #[test]
+    fn parents_by_id_follow_the_recorded_parent() {
+        use crate::NO_SPAN;
+        // 1 overlaps 0 in time but was recorded as a sibling, and 3 names a later id as its parent
+        let traces = [
+            with_ids(0, NO_SPAN, Trace::new(1, 0, 10)),
+            with_ids(1, NO_SPAN, Trace::new(2, 2, 4)),
+            with_ids(2, 0, Trace::new(3, 5, 6)),
+            with_ids(3, 4, Trace::new(4, 7, 8)),
+            with_ids(4, NO_SPAN, Trace::new(5, 11, 12)),
+        ];
+        assert_eq!(parents_by_id(&traces), [None, None, Some(0), None, None]);
+        assert_eq!(costs(&traces[..2]), [cost(1, 1, 10, 10), cost(2, 1, 2, 2)]);
+    }
+
+    #[cfg(feature = "nesting")]
+    #[test]
+    fn traces_without_ids_fall_back_to_parents_by_time() {
+        use crate::NO_SPAN;
+        let traces = [with_ids(0, NO_SPAN, Trace::new(1, 0, 10)), Trace::new(2, 2, 4)];
+        assert_eq!(costs(&traces), [cost(1, 1, 10, 8), cost(2, 1, 2, 2)]);
+    }
+}
@@ -0,0 +1,59 @@
+//! Conversion between [`ActiveClock`] ticks and nanoseconds.
+
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::{Duration, Instant};
+
+use crate::{ActiveClock, ClockSource};
+
+/// f64 bits of the cached ticks per nanosecond, 0 until calibrated
+static TICKS_PER_NS: AtomicU64 = AtomicU64::new(0);
+
+/// How long [`ticks_per_ns`] measures for, the first time it's called.
+pub const DEFAULT_CALIBRATION: Duration = Duration::from_millis(10);
+
+/// Measures ticks per nanosecond by comparing the [`ActiveClock`] against [`Instant`] over `duration`,
+/// and caches the result for [`ticks_per_ns`].
+/// Longer durations give more accurate results. Sleeps the calling thread for that long.
+/// If the clock didn't advance, assumes one tick per nanosecond.
+///
+/// Clocks with a known rate (e.g. `cntvct_el0` on aarch64, whose frequency is in `cntfrq_el0`,
+/// or `clock_gettime` in nanoseconds) report it through [`ClockSource::ticks_per_ns`] instead, and `duration` is ignored.
+pub fn calibrate(duration: Duration) -> f64 {
+    let ticks_per_ns = ActiveClock::ticks_per_ns().unwrap_or_else(|| {
+        let (start, start_ticks) = now_and_ticks();
+        std::thread::sleep(duration);
+        let (stop, stop_ticks) = now_and_ticks();
+        let ns = stop.duration_since(start).as_nanos() as f64;
+        let measured = stop_ticks.wrapping_sub(start_ticks) as f64 / ns;
+        // e.g. a custom clock that doesn't advance on its own
+        if measured > 0.0 && measured.is_finite() {
+            measured
+        } else {
+            1.0
+        }
+    });
+    TICKS_PER_NS.store(ticks_per_ns.to_bits(), Relaxed);
+    ticks_per_ns
+}
+
+/// Reads the tick count as close as possible to the wall clock reading, by taking the midpoint of two tick reads.
+fn now_and_ticks() -> (Instant, u64) {
+    let before = ActiveClock::now();
+    let now = Instant::now();
+    let after = ActiveClock::now();
+    (now, before + after.wrapping_sub(before) / 2)
+}
+
+/// Ticks of the [`ActiveClock`] per nanosecond.
+/// Calibrates for [`DEFAULT_CALIBRATION`] on first use, unless [`calibrate`] was already called.
+pub fn ticks_per_ns() -> f64 {
+    match TICKS_PER_NS.load(Relaxed) {
+        0 => calibrate(DEFAULT_CALIBRATION),
+        bits => f64::from_bits(bits),
+    }
+}
+
+/// Converts a number of ticks (e.g. stop minus start of a trace) to nanoseconds, using [`ticks_per_ns`].
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    (ticks as f64 / ticks_per_ns()).round() as u64
+}
@@ -0,0 +1,338 @@
+//! Export to the Chrome Trace Event JSON format, for chrome://tracing or <https://ui.perfetto.dev>.
+//!
+//! See <https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU>
+
+use std::collections::BTreeMap;
+use std::io::{Read, Result, Write};
+
+use crate::{registry, tags, ticks_per_ns, ActiveClock, ClockSource, FlowSpans, RecordKind, Trace, TraceReader, TraceStats};
+
+/// Writes the current thread's traces as Chrome Trace Event JSON.
+/// See [`write_all_threads_chrome_json`] for the details.
+pub fn write_traces_chrome_json(writer: &mut impl Write) -> Result<()> {
+    let mut json = ChromeJson::in_process(writer)?;
+    registry::with_current_entry(|thread_id, buffer| -> Result<()> {
+        if let Some(name) = std::thread::current().name() {
+            json.thread_name(thread_id, name)?;
+        }
+        json.thread(TraceStats::new(thread_id, buffer.counts()), buffer.records())
+    })?;
+    json.finish()
+}
+
+/// Writes the traces of every thread that has recorded any as Chrome Trace Event JSON,
+/// which chrome://tracing and <https://ui.perfetto.dev> can open.
+///
+/// Each trace is a complete ("X") event, with timestamps converted to microseconds since the first thread recorded a trace
+/// using [`ticks_per_ns`], this library's thread ids as `tid`, and names given to [`register_tag`](crate::register_tag) as `name`.
+/// Tags without a name are named by their number.
+/// Flows from [`flow_start`](crate::flow_start) and [`flow_step`](crate::flow_step) are flow ("s" and "t") events,
+/// which chrome://tracing draws as arrows between the slices enclosing them, placed inside the span each was recorded in
+/// (see [`FlowSpans`]).
+/// Instants from `trace_instant!` are thread scoped instant ("i") events, and samples from `trace_counter!` are counter ("C") events.
+/// With the `"cpu_id"` feature, `args` also has the CPU ids, and spans that migrated between CPUs are drawn in red.
+/// With the `"nesting"` feature, `args` also has each span's id, parent and depth.
+/// With the `"thread_id"` feature, `args` of spans from [`begin`](crate::begin) also has the threads they started and stopped on.
+/// With the `"payload"` feature, `args` also has the values given to `trace_span!(tag, arg0, arg1)`, as arg0 and arg1.
+/// The clock traces were timed with is recorded under `otherData`.
+/// A thread that lost traces starts with a "traces lost" instant event, see [`TraceStats`].
+///
+/// Includes the same threads as [`write_all_threads_binary`](crate::write_all_threads_binary).
+pub fn write_all_threads_chrome_json(writer: &mut impl Write) -> Result<()> {
+    let mut json = ChromeJson::in_process(writer)?;
+    registry::for_each_thread(|thread| {
+        if let Some(name) = &thread.thread_name {
+            json.thread_name(thread.thread_id, name)?;
+        }
+        let stats = TraceStats::new(thread.thread_id, thread.counts);
+        json.thread(stats, thread.traces.into_iter())
+    })?;
+    json.finish()
+}
+
+/// Converts traces read from a file, e.g. by [`TraceReader::open`], to Chrome Trace Event JSON.
+///
+/// Files written by [`write_trace_file`](crate::write_trace_file) carry their own ticks per nanosecond, tag names and threads.
+/// For bare traces from [`write_traces_binary`](crate::write_traces_binary), the ticks per nanosecond of the machine doing
+/// the conversion are used, timestamps are relative to when the counter started (usually boot), and everything is on a single thread.
+pub fn write_chrome_json_from<R: Read>(mut reader: TraceReader<R>, writer: &mut impl Write) -> Result<()> {
+    let tag_names = reader.tag_names().clone();
+    let pid = reader.pid();
+    let (ticks_per_ns, base_ticks) = match reader.header() {
+        Some(header) => (header.ticks_per_ns, header.start_ticks),
+        None => (ticks_per_ns(), 0),
+    };
+    let clock = reader.metadata_value("clock").map(str::to_owned);
+    let mut json = ChromeJson::new(writer, pid, ticks_per_ns, base_ticks, tag_names, clock.as_deref())?;
+    let mut named_thread = None;
+    while let Some(trace) = reader.next() {
+        let trace = trace?;
+        let thread_id = reader.thread_id().unwrap_or(1);
+        if named_thread != Some(thread_id) {
+            if let Some(previous) = named_thread {
+                json.end_thread(previous)?;
+            }
+            named_thread = Some(thread_id);
+            if let Some(name) = reader.thread_name() {
+                json.thread_name(thread_id, name)?;
+            }
+            if let Some(stats) = reader.thread_stats() {
+                json.lost(&stats, trace.start)?;
+            }
+        }
+        json.record(thread_id, &trace)?;
+    }
+    if let Some(thread_id) = named_thread {
+        json.end_thread(thread_id)?;
+    }
+    json.finish()
+}
+
+/// Writes events one at a time, holding back only flows until the span they were recorded in.
+struct ChromeJson<'a, W: Write> {
+    writer: &'a mut W,
+    pid: u64,
+    ticks_per_ns: f64,
+    /// ticks at timestamp zero
+    base_ticks: u64,
+    tag_names: BTreeMap<u64, String>,
+    first: bool,
+    /// flows of the thread being written, waiting for their span
+    flows: FlowSpans,
+}
+
+impl<'a, W: Write> ChromeJson<'a, W> {
+    fn new(
+        writer: &'a mut W,
+        pid: u64,
+        ticks_per_ns: f64,
+        base_ticks: u64,
+        tag_names: BTreeMap<u64, String>,
+        clock: Option<&str>,
+    ) -> Result<Self> {
+        writer.write_all(b"{\"displayTimeUnit\":\"ns\",")?;
+        if let Some(clock) = clock {
+            writer.write_all(b"\"otherData\":{\"clock\":\"")?;
+            write_escaped(writer, clock)?;
+            writer.write_all(b"\"},")?;
+        }
+        writer.write_all(b"\"traceEvents\":[")?;
+        Ok(ChromeJson {
+            writer,
+            pid,
+            ticks_per_ns,
+            base_ticks,
+            tag_names,
+            first: true,
+            flows: FlowSpans::new(),
+        })
+    }
+
+    /// For traces recorded by this process, calibrating first if that hasn't been done yet.
+    fn in_process(writer: &'a mut W) -> Result<Self> {
+        let ticks_per_ns = ticks_per_ns();
+        let (_, base_ticks) = registry::capture_start();
+        let pid = std::process::id() as u64;
+        let clock = Some(ActiveClock::name());
+        ChromeJson::new(writer, pid, ticks_per_ns, base_ticks, tags::tag_names(), clock)
+    }
+
+    fn separator(&mut self) -> Result<()> {
+        if self.first {
+            self.first = false;
+            self.writer.write_all(b"\n")
+        } else {
+            self.writer.write_all(b",\n")
+        }
+    }
+
+    fn thread_name(&mut self, thread_id: u64, name: &str) -> Result<()> {
+        self.separator()?;
+        write!(
+            self.writer,
+            "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":{},\"tid\":{thread_id},\"args\":{{\"name\":\"",
+            self.pid
+        )?;
+        write_escaped(self.writer, name)?;
+        self.writer.write_all(b"\"}}")
+    }
+
+    /// Writes a thread's traces, oldest to newest, after a "traces lost" event if it lost any.
+    fn thread(&mut self, stats: TraceStats, traces: impl Iterator<Item = Trace>) -> Result<()> {
+        for (i, trace) in traces.enumerate() {
+            if i == 0 {
+                self.lost(&stats, trace.start)?;
+            }
+            self.record(stats.thread_id, &trace)?;
+        }
+        self.end_thread(stats.thread_id)
+    }
+
+    /// Writes the flows of the thread whose traces have all been recorded that no span claimed, at their own time.
+    fn end_thread(&mut self, thread_id: u64) -> Result<()> {
+        let unclaimed: Vec<Trace> = self.flows.finish().map(|(flow, _)| flow).collect();
+        for flow in &unclaimed {
+            self.flow(thread_id, flow, None)?;
+        }
+        Ok(())
+    }
+
+    /// Writes an instant event at `ticks` saying how many traces the thread lost, if any.
+    fn lost(&mut self, stats: &TraceStats, ticks: u64) -> Result<()> {
+        let Some((name, args)) = stats.lost_event() else {
+            return Ok(());
+        };
+        self.separator()?;
+        let ts = self.micros(ticks.wrapping_sub(self.base_ticks) as i64);
+        write!(
+            self.writer,
+            "{{\"name\":\"{name}\",\"cat\":\"tsc-trace\",\"ph\":\"i\",\"s\":\"t\",\"ts\":{ts:.3},\"pid\":{},\"tid\":{},\"args\":{{",
+            self.pid, stats.thread_id
+        )?;
+        for (i, (arg, n)) in args.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            write!(self.writer, "{separator}\"{arg}\":{n}")?;
+        }
+        self.writer.write_all(b"}}")
+    }
+
+    /// Microseconds since `base_ticks`, which Chrome expects for `ts` and `dur`.
+    fn micros(&self, ticks: i64) -> f64 {
+        ticks as f64 / self.ticks_per_ns / 1000.0
+    }
+
+    /// Writes a trace as the event for its kind, skipping kinds this build doesn't know.
+    /// Flows are written after the span they were recorded in, see [`FlowSpans`].
+    fn record(&mut self, thread_id: u64, trace: &Trace) -> Result<()> {
+        let claimed: Vec<Trace> = self.flows.push(trace).collect();
+        match trace.kind() {
+            RecordKind::Span => {
+                self.complete(thread_id, trace)?;
+                for flow in &claimed {
+                    self.flow(thread_id, flow, Some(trace))?;
+                }
+                Ok(())
+            }
+            RecordKind::FlowStart | RecordKind::FlowStep => Ok(()),
+            RecordKind::Instant => self.instant(thread_id, trace),
+            RecordKind::Counter => self.counter(thread_id, trace),
+            RecordKind::Unknown(_) => Ok(()),
+        }
+    }
+
+    /// Writes a flow event, which binds to the slice enclosing it on the same thread, in `span` if that's given.
+    fn flow(&mut self, thread_id: u64, trace: &Trace, span: Option<&Trace>) -> Result<()> {
+        self.separator()?;
+        let phase = if trace.kind() == RecordKind::FlowStart { "s" } else { "t" };
+        let mut ts = self.micros(trace.start.wrapping_sub(self.base_ticks) as i64);
+        if let Some(span) = span.filter(|span| trace.start >= span.stop && span.stop > span.start) {
+            // chrome://tracing binds a flow at the very end of a span to a span starting then, so move it just inside,
+            // by a nanosecond, as the span's start and stop are written
+            let written = |ticks: u64| (self.micros(ticks.wrapping_sub(self.base_ticks) as i64) * 1000.0).round() / 1000.0;
+            ts = (written(span.stop) - 0.001).max(written(span.start));
+        }
+        write!(
+            self.writer,
+            "{{\"name\":\"flow\",\"cat\":\"tsc-trace\",\"ph\":\"{phase}\",\"bp\":\"e\",\"id\":{},\"ts\":{ts:.3},\"pid\":{},\"tid\":{thread_id}}}",
+            trace.stop, self.pid
+        )
+    }
+
+    /// Starts an event with the tag's name.
+    fn named(&mut self, trace: &Trace) -> Result<()> {
+        self.separator()?;
+        self.writer.write_all(b"{\"name\":\"")?;
+        match self.tag_names.get(&trace.base_tag()) {
+            Some(name) => write_escaped(self.writer, name),
+            None => write!(self.writer, "{}", trace.base_tag()),
+        }
+    }
+
+    /// Writes an instant event, scoped to its thread.
+    fn instant(&mut self, thread_id: u64, trace: &Trace) -> Result<()> {
+        self.named(trace)?;
+        let ts = self.micros(trace.start.wrapping_sub(self.base_ticks) as i64);
+        write!(
+            self.writer,
+            "\",\"cat\":\"tsc-trace\",\"ph\":\"i\",\"s\":\"t\",\"ts\":{ts:.3},\"pid\":{},\"tid\":{thread_id},\"args\":{{\"tag\":{}}}}}",
+            self.pid,
+            trace.base_tag()
+        )
+    }
+
+    /// Writes a counter event, which Chrome draws as a graph per name for the whole process.
+    fn counter(&mut self, thread_id: u64, trace: &Trace) -> Result<()> {
+        self.named(trace)?;
+        let ts = self.micros(trace.start.wrapping_sub(self.base_ticks) as i64);
+        write!(
+            self.writer,
+            "\",\"cat\":\"tsc-trace\",\"ph\":\"C\",\"ts\":{ts:.3},\"pid\":{},\"tid\":{thread_id},\"args\":{{\"value\":{}}}}}",
+            self.pid, trace.stop
+        )
+    }
+
+    fn complete(&mut self, thread_id: u64, trace: &Trace) -> Result<()> {
+        self.named(trace)?;
+        let ts = self.micros(trace.start.wrapping_sub(self.base_ticks) as i64);
+        let dur = self.micros(trace.stop.wrapping_sub(trace.start) as i64);
+        write!(
+            self.writer,
+            "\",\"cat\":\"tsc-trace\",\"ph\":\"X\",\"ts\":{ts:.3},\"dur\":{dur:.3},\"pid\":{},\"tid\":{thread_id},\"args\":{{\"tag\":{},\"start\":{},\"stop\":{}",
+            self.pid, trace.tag, trace.start, trace.stop
+        )?;
+        #[cfg(feature = "cpu_id")]
+        if trace.start_cpu != crate::NO_CPU {
+            write!(
+                self.writer,
+                ",\"start_cpu\":{},\"stop_cpu\":{},\"migrated\":{}",
+                trace.start_cpu,
+                trace.stop_cpu,
+                trace.migrated()
+            )?;
+        }
+        #[cfg(feature = "nesting")]
+        if trace.id != crate::NO_SPAN {
+            write!(self.writer, ",\"id\":{},\"depth\":{}", trace.id, trace.depth)?;
+            if trace.parent != crate::NO_SPAN {
+                write!(self.writer, ",\"parent\":{}", trace.parent)?;
+            }
+        }
+        #[cfg(feature = "thread_id")]
+        if trace.start_thread != crate::NO_THREAD {
+            write!(
+                self.writer,
+                ",\"start_thread\":{},\"stop_thread\":{}",
+                trace.start_thread, trace.stop_thread
+            )?;
+        }
+        #[cfg(feature = "payload")]
+        for (i, &arg) in trace.args.iter().enumerate() {
+            if arg != crate::NO_ARG {
+                write!(self.writer, ",\"arg{i}\":{arg}")?;
+            }
+        }
+        #[cfg(feature = "cpu_id")]
+        if trace.migrated() {
+            // chrome://tracing draws these in red
+            return self.writer.write_all(b"},\"cname\":\"terrible\"}");
+        }
+        self.writer.write_all(b"}}")
+    }
+
+    fn finish(self) -> Result<()> {
+        self.writer.write_all(b"\n]}\n")
+    }
+}
+
+/// Writes the contents of a JSON string, escaping quotes, backslashes and control characters.
+fn write_escaped(writer: &mut impl Write, s: &str) -> Result<()> {
+    for c in s.chars() {
+        match c {
+            '"' => writer.write_all(b"\\\"")?,
+            '\\' => writer.write_all(b"\\\\")?,
+            c if (c as u32) < 0x20 => write!(writer, "\\u{:04x}", c as u32)?,
+            c => write!(writer, "{c}")?,
+        }
+    }
+    Ok(())
+}
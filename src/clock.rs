@@ -0,0 +1,423 @@
+//! Clocks that spans can be timed with, one of which is chosen at compile time by features.
+
+use std::cell::Cell;
+use std::sync::OnceLock;
+
+use crate::NO_CPU;
+
+/// A source of timestamps for traces.
+///
+/// Which one [`TraceSpan`](crate::TraceSpan) uses is fixed at compile time, see [`ActiveClock`],
+/// so reading it costs no more than calling the underlying instruction directly.
+pub trait ClockSource {
+    /// Short name recorded in trace file metadata under "clock", e.g. "rdtsc".
+    fn name() -> &'static str;
+
+    /// Reads the clock, in ticks of whatever rate it runs at.
+    fn now() -> u64;
+
+    /// Reads the clock along with the id of the CPU it was read on, or [`NO_CPU`] if that can't be known.
+    /// Used by [`TraceSpan`](crate::TraceSpan) with the `"cpu_id"` feature.
+    #[inline(always)]
+    fn now_with_cpu() -> (u64, u32) {
+        (Self::now(), current_cpu())
+    }
+
+    /// Ticks per nanosecond, if the rate is fixed and can be known without measuring it against the system clock.
+    fn ticks_per_ns() -> Option<f64> {
+        None
+    }
+}
+
+/// The CPU the calling thread is running on, from `sched_getcpu`, or [`NO_CPU`] where that isn't available.
+#[inline(always)]
+pub fn current_cpu() -> u32 {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let cpu = unsafe { libc::sched_getcpu() };
+        if cpu >= 0 {
+            return cpu as u32;
+        }
+    }
+    NO_CPU
+}
+
+/// x86 `rdtsc`, the processor's timestamp counter. The default on x86.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct Rdtsc;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl ClockSource for Rdtsc {
+    fn name() -> &'static str {
+        "rdtsc"
+    }
+
+    #[inline(always)]
+    fn now() -> u64 {
+        #[cfg(target_arch = "x86")]
+        use core::arch::x86::_rdtsc;
+        #[cfg(target_arch = "x86_64")]
+        use core::arch::x86_64::_rdtsc;
+        unsafe { _rdtsc() }
+    }
+
+    #[inline(always)]
+    fn now_with_cpu() -> (u64, u32) {
+        crate::rdtscp()
+    }
+}
+
+/// x86 `rdtsc` between two `lfence` instructions, so it isn't reordered with the code being timed.
+/// The default on x86 with the `"lfence"` feature.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct LfenceRdtsc;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl ClockSource for LfenceRdtsc {
+    fn name() -> &'static str {
+        "lfence_rdtsc"
+    }
+
+    #[inline(always)]
+    fn now() -> u64 {
+        #[cfg(target_arch = "x86")]
+        use core::arch::x86::{_mm_lfence, _rdtsc};
+        #[cfg(target_arch = "x86_64")]
+        use core::arch::x86_64::{_mm_lfence, _rdtsc};
+        unsafe {
+            _mm_lfence();
+            let r = _rdtsc();
+            _mm_lfence();
+            r
+        }
+    }
+
+    #[inline(always)]
+    fn now_with_cpu() -> (u64, u32) {
+        crate::rdtscp()
+    }
+}
+
+/// x86 `rdtscp`, which waits for earlier instructions to finish and also reads the CPU id. Chosen by the `"clock_rdtscp"` feature.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct Rdtscp;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl ClockSource for Rdtscp {
+    fn name() -> &'static str {
+        "rdtscp"
+    }
+
+    #[inline(always)]
+    fn now() -> u64 {
+        crate::rdtscp().0
+    }
+
+    #[inline(always)]
+    fn now_with_cpu() -> (u64, u32) {
+        crate::rdtscp()
+    }
+}
+
+/// aarch64 `cntvct_el0`, the virtual counter. The default on aarch64.
+#[cfg(target_arch = "aarch64")]
+pub struct Cntvct;
+
+#[cfg(target_arch = "aarch64")]
+impl ClockSource for Cntvct {
+    fn name() -> &'static str {
+        "cntvct_el0"
+    }
+
+    #[inline(always)]
+    fn now() -> u64 {
+        let r: u64;
+        unsafe {
+            std::arch::asm!("mrs {}, cntvct_el0", out(reg) r);
+        }
+        r
+    }
+
+    /// From `cntfrq_el0`, the counter's frequency, which is fixed by the hardware.
+    fn ticks_per_ns() -> Option<f64> {
+        let hz: u64;
+        unsafe {
+            std::arch::asm!("mrs {}, cntfrq_el0", out(reg) hz);
+        }
+        Some(hz as f64 / 1e9)
+    }
+}
+
+/// RISC-V `rdtime`, a constant rate counter (often only a few MHz). The default on RISC-V.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub struct RiscvRdtime;
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+impl ClockSource for RiscvRdtime {
+    fn name() -> &'static str {
+        "rdtime"
+    }
+
+    #[inline(always)]
+    fn now() -> u64 {
+        #[cfg(target_arch = "riscv64")]
+        {
+            let r: u64;
+            unsafe {
+                std::arch::asm!("csrr {}, time", out(reg) r);
+            }
+            r
+        }
+        #[cfg(target_arch = "riscv32")]
+        loop {
+            let (high, low, high_again): (u32, u32, u32);
+            unsafe {
+                std::arch::asm!("csrr {}, timeh", "csrr {}, time", "csrr {}, timeh", out(reg) high, out(reg) low, out(reg) high_again);
+            }
+            // retry if the low half wrapped between reads
+            if high == high_again {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+}
+
+/// RISC-V `rdcycle`, the cycle counter. Chosen by the `"clock_rdcycle"` feature.
+/// Linux only allows reading it from user space if `/proc/sys/kernel/perf_user_access` is set.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub struct RiscvRdcycle;
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+impl ClockSource for RiscvRdcycle {
+    fn name() -> &'static str {
+        "rdcycle"
+    }
+
+    #[inline(always)]
+    fn now() -> u64 {
+        #[cfg(target_arch = "riscv64")]
+        {
+            let r: u64;
+            unsafe {
+                std::arch::asm!("csrr {}, cycle", out(reg) r);
+            }
+            r
+        }
+        #[cfg(target_arch = "riscv32")]
+        loop {
+            let (high, low, high_again): (u32, u32, u32);
+            unsafe {
+                std::arch::asm!("csrr {}, cycleh", "csrr {}, cycle", "csrr {}, cycleh", out(reg) high, out(reg) low, out(reg) high_again);
+            }
+            // retry if the low half wrapped between reads
+            if high == high_again {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+}
+
+/// `clock_gettime(CLOCK_MONOTONIC_RAW)` in nanoseconds, through the vDSO on most Linux targets.
+/// Slower than a counter instruction, but works everywhere (falling back to `CLOCK_MONOTONIC` on Unix without it).
+/// Chosen by the `"clock_monotonic_raw"` feature, and the default on architectures without a supported counter.
+#[cfg(unix)]
+pub struct MonotonicRaw;
+
+#[cfg(unix)]
+impl ClockSource for MonotonicRaw {
+    fn name() -> &'static str {
+        "clock_monotonic_raw"
+    }
+
+    #[inline(always)]
+    fn now() -> u64 {
+        #[cfg(any(target_os = "linux", target_os = "android", target_vendor = "apple"))]
+        const CLOCK: libc::clockid_t = libc::CLOCK_MONOTONIC_RAW;
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_vendor = "apple")))]
+        const CLOCK: libc::clockid_t = libc::CLOCK_MONOTONIC;
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe {
+            libc::clock_gettime(CLOCK, &mut time);
+        }
+        time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+    }
+
+    fn ticks_per_ns() -> Option<f64> {
+        Some(1.0)
+    }
+}
+
+struct Custom {
+    name: &'static str,
+    now: fn() -> u64,
+}
+
+static CUSTOM_CLOCK: OnceLock<Custom> = OnceLock::new();
+
+/// Calls the function given to [`set_custom_clock`]. Chosen by the `"clock_custom"` feature.
+/// Reads 0 until that's called.
+pub struct CustomClock;
+
+impl ClockSource for CustomClock {
+    fn name() -> &'static str {
+        CUSTOM_CLOCK.get().map_or("custom", |custom| custom.name)
+    }
+
+    #[inline(always)]
+    fn now() -> u64 {
+        CUSTOM_CLOCK.get().map_or(0, |custom| (custom.now)())
+    }
+}
+
+/// Sets the clock read by [`CustomClock`], and the name recorded for it in trace file metadata.
+/// Should be called before any spans are traced, and can only be called once; returns false if it already was.
+pub fn set_custom_clock(name: &'static str, now: fn() -> u64) -> bool {
+    CUSTOM_CLOCK.set(Custom { name, now }).is_ok()
+}
+
+thread_local! {
+    static MOCK_NOW: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A clock for tests, which only moves when told to, chosen by the `"mock_clock"` feature (overriding any other clock).
+/// Each thread has its own, starting at 0, so tests running in parallel don't affect each other.
+/// Ticks are treated as nanoseconds.
+///
+/// Together with [`traces_in_order`](crate::traces_in_order), this lets tests check exactly which traces instrumented code records.
+pub struct MockClock;
+
+impl MockClock {
+    /// Moves the current thread's clock forward by `ticks`.
+    pub fn advance(ticks: u64) {
+        MOCK_NOW.with(|now| now.set(now.get() + ticks));
+    }
+
+    /// Sets the current thread's clock to `ticks`.
+    pub fn set(ticks: u64) {
+        MOCK_NOW.with(|now| now.set(ticks));
+    }
+}
+
+impl ClockSource for MockClock {
+    fn name() -> &'static str {
+        "mock"
+    }
+
+    #[inline(always)]
+    fn now() -> u64 {
+        MOCK_NOW.with(Cell::get)
+    }
+
+    fn ticks_per_ns() -> Option<f64> {
+        Some(1.0)
+    }
+}
+
+/// The clock [`TraceSpan`](crate::TraceSpan) uses: [`MockClock`] with the `"mock_clock"` feature.
+#[cfg(feature = "mock_clock")]
+pub type ActiveClock = MockClock;
+
+/// The clock [`TraceSpan`](crate::TraceSpan) uses: [`CustomClock`] with the `"clock_custom"` feature.
+#[cfg(all(feature = "clock_custom", not(feature = "mock_clock")))]
+pub type ActiveClock = CustomClock;
+
+/// The clock [`TraceSpan`](crate::TraceSpan) uses: [`MonotonicRaw`] with the `"clock_monotonic_raw"` feature,
+/// or on architectures without a supported counter.
+#[cfg(all(
+    not(any(feature = "mock_clock", feature = "clock_custom")),
+    any(
+        feature = "clock_monotonic_raw",
+        not(any(
+            target_arch = "x86",
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv32",
+            target_arch = "riscv64"
+        ))
+    )
+))]
+pub type ActiveClock = MonotonicRaw;
+
+/// The clock [`TraceSpan`](crate::TraceSpan) uses: [`Rdtscp`] with the `"clock_rdtscp"` feature.
+#[cfg(all(
+    not(any(feature = "mock_clock", feature = "clock_custom", feature = "clock_monotonic_raw")),
+    feature = "clock_rdtscp",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+pub type ActiveClock = Rdtscp;
+
+/// The clock [`TraceSpan`](crate::TraceSpan) uses: [`LfenceRdtsc`] with the `"lfence"` feature.
+#[cfg(all(
+    not(any(feature = "mock_clock", feature = "clock_custom", feature = "clock_monotonic_raw", feature = "clock_rdtscp")),
+    feature = "lfence",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+pub type ActiveClock = LfenceRdtsc;
+
+/// The clock [`TraceSpan`](crate::TraceSpan) uses: [`Rdtsc`] by default on x86.
+#[cfg(all(
+    not(any(
+        feature = "mock_clock",
+        feature = "clock_custom",
+        feature = "clock_monotonic_raw",
+        feature = "clock_rdtscp",
+        feature = "lfence"
+    )),
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+pub type ActiveClock = Rdtsc;
+
+/// The clock [`TraceSpan`](crate::TraceSpan) uses: [`Cntvct`] by default on aarch64.
+#[cfg(all(
+    not(any(feature = "mock_clock", feature = "clock_custom", feature = "clock_monotonic_raw")),
+    target_arch = "aarch64"
+))]
+pub type ActiveClock = Cntvct;
+
+/// The clock [`TraceSpan`](crate::TraceSpan) uses: [`RiscvRdcycle`] with the `"clock_rdcycle"` feature.
+#[cfg(all(
+    not(any(feature = "mock_clock", feature = "clock_custom", feature = "clock_monotonic_raw")),
+    feature = "clock_rdcycle",
+    any(target_arch = "riscv32", target_arch = "riscv64")
+))]
+pub type ActiveClock = RiscvRdcycle;
+
+/// The clock [`TraceSpan`](crate::TraceSpan) uses: [`RiscvRdtime`] by default on RISC-V.
+#[cfg(all(
+    not(any(feature = "mock_clock", feature = "clock_custom", feature = "clock_monotonic_raw", feature = "clock_rdcycle")),
+    any(target_arch = "riscv32", target_arch = "riscv64")
+))]
+pub type ActiveClock = RiscvRdtime;
+
+/// Reads the [`ActiveClock`], as [`TraceSpan`](crate::TraceSpan) does.
+/// Use this rather than [`rdtsc`](crate::rdtsc) for times passed to `insert_trace!`, so they match.
+#[inline(always)]
+pub fn now() -> u64 {
+    ActiveClock::now()
+}
+
+#[cfg(all(test, feature = "mock_clock", not(feature = "off")))]
+mod tests {
+    use super::*;
+    use crate::{clear_thread_traces, traces_in_order, TraceSpan};
+
+    #[test]
+    fn mock_clock_times_spans() {
+        clear_thread_traces();
+        MockClock::set(100);
+        {
+            crate::trace_span!(1);
+            MockClock::advance(10);
+            {
+                crate::trace_span!(2);
+                MockClock::advance(5);
+            }
+            MockClock::advance(1);
+        }
+        let spans: Vec<_> = traces_in_order().map(|trace| (trace.tag, trace.start, trace.stop)).collect();
+        assert_eq!(spans, [(2, 110, 115), (1, 100, 116)]);
+    }
+}
@@ -0,0 +1,635 @@
+//! Self-describing trace files: a header, a table of tag names, then each thread's traces.
+//!
+//! All integers are in the byte order of the machine that wrote the file, which the header records.
+//! Strings are a u32 length in bytes followed by that much UTF-8.
+//!
+//! ```text
+//! magic: [u8; 8]            b"TSCTRACE"
+//! endianness: u16           0x0102, read as 0x0201 if the file's byte order differs from the reader's
+//! version: u16              FILE_VERSION
+//! record_size: u32          bytes per trace
+//! fields: u32               which optional fields follow tag, start and stop in each trace, see FIELD_CPU_ID
+//! ticks_per_ns: f64
+//! start_unix_ns: u64        wall clock when the first thread recorded a trace
+//! start_ticks: u64          the clock traces were timed with, at that same moment
+//! metadata_count: u32
+//!   key: string, value: string      e.g. "host", "cpu", "os", "arch", "pid", "clock"
+//! tag_count: u32
+//!   tag: u64, name: string
+//! thread_count: u32
+//!   thread_id: u64
+//!   thread_name: string     empty if the thread was unnamed
+//!   recorded: u64           traces ever recorded by the thread
+//!   overwritten: u64        of those, how many were overwritten before being written to the file
+//!   dropped: u64            traces not recorded because the buffer was full, see FullPolicy::Stop
+//!   trace_count: u64
+//!   traces: [record_size bytes; trace_count], oldest to newest, each:
+//!     tag: u64, start: u64, stop: u64
+//!     start_cpu: u32, stop_cpu: u32       if fields has FIELD_CPU_ID
+//!     id: u64, parent: u64, depth: u64    if fields has FIELD_NESTING
+//!     start_thread: u32, stop_thread: u32 if fields has FIELD_THREAD_ID
+//!     arg0: u64, arg1: u64                if fields has FIELD_PAYLOAD
+//! ```
+//!
+//! Readers skip any bytes at the end of a record beyond the fields they know.
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::{registry, tags, ticks_per_ns, ActiveClock, ClockSource, Trace};
+
+/// First bytes of every trace file.
+pub const FILE_MAGIC: [u8; 8] = *b"TSCTRACE";
+
+/// Version of the trace file format written by [`write_trace_file`].
+pub const FILE_VERSION: u16 = 1;
+
+const ENDIANNESS: u16 = 0x0102;
+
+/// Size in bytes of each trace written by this build.
+pub(crate) const RECORD_SIZE: u32 = std::mem::size_of::<Trace>() as u32;
+
+/// Bit set in [`TraceFileHeader::fields`] when each trace includes the CPUs it started and stopped on,
+/// as recorded with the `"cpu_id"` feature.
+pub const FIELD_CPU_ID: u32 = 1;
+
+/// Bit set in [`TraceFileHeader::fields`] when each trace includes its span id, parent and depth,
+/// as recorded with the `"nesting"` feature.
+pub const FIELD_NESTING: u32 = 2;
+
+/// Bit set in [`TraceFileHeader::fields`] when each trace includes the threads it started and stopped on,
+/// as recorded for spans from [`begin`](crate::begin) with the `"thread_id"` feature.
+pub const FIELD_THREAD_ID: u32 = 4;
+
+/// Bit set in [`TraceFileHeader::fields`] when each trace includes the values given to `trace_span!(tag, arg0, arg1)`,
+/// as recorded with the `"payload"` feature.
+pub const FIELD_PAYLOAD: u32 = 8;
+
+/// Each optional field's bit and size in bytes, in the order they appear in a trace.
+const FIELD_SIZES: [(u32, u32); 4] = [(FIELD_CPU_ID, 8), (FIELD_NESTING, 24), (FIELD_THREAD_ID, 8), (FIELD_PAYLOAD, 16)];
+
+/// Optional fields in each trace written by this build.
+pub(crate) const FIELDS: u32 = if cfg!(feature = "cpu_id") { FIELD_CPU_ID } else { 0 }
+    | if cfg!(feature = "nesting") { FIELD_NESTING } else { 0 }
+    | if cfg!(feature = "thread_id") { FIELD_THREAD_ID } else { 0 }
+    | if cfg!(feature = "payload") { FIELD_PAYLOAD } else { 0 };
+
+/// Writes the traces of every thread that has recorded any, in the self-describing format described in the [`file`](self) module,
+/// along with every tag name given to [`register_tag`](crate::register_tag).
+///
+/// Includes the same threads as [`write_all_threads_binary`](crate::write_all_threads_binary),
+/// and calibrates [`ticks_per_ns`] first if that hasn't been done yet.
+/// Every thread's traces are copied before any are written, so threads aren't held up by a slow writer,
+/// and this needs memory for a copy of them all.
+pub fn write_trace_file(writer: &mut impl Write) -> Result<()> {
+    let ticks_per_ns = ticks_per_ns();
+    let (start_unix_ns, start_ticks) = registry::capture_start();
+    let mut out = Output(writer);
+    out.0.write_all(&FILE_MAGIC)?;
+    out.u16(ENDIANNESS)?;
+    out.u16(FILE_VERSION)?;
+    out.u32(RECORD_SIZE)?;
+    out.u32(FIELDS)?;
+    out.u64(ticks_per_ns.to_bits())?;
+    out.u64(start_unix_ns)?;
+    out.u64(start_ticks)?;
+
+    let metadata = host_metadata();
+    out.u32(metadata.len() as u32)?;
+    for (key, value) in &metadata {
+        out.str(key)?;
+        out.str(value)?;
+    }
+
+    let tag_names = tags::tag_names();
+    out.u32(tag_names.len() as u32)?;
+    for (tag, name) in &tag_names {
+        out.u64(*tag)?;
+        out.str(name)?;
+    }
+
+    // copied up front, as the number of threads comes first
+    let threads = registry::copy_threads();
+    out.u32(threads.len() as u32)?;
+    for thread in threads {
+        let (written, held, dropped) = thread.counts;
+        out.u64(thread.thread_id)?;
+        out.str(thread.thread_name.as_deref().unwrap_or(""))?;
+        out.u64(written)?;
+        out.u64(written.saturating_sub(held))?;
+        out.u64(dropped)?;
+        out.u64(held)?;
+        crate::write_words(out.0, thread.traces.into_iter().flat_map(Trace::words))?;
+    }
+    Ok(())
+}
+
+/// Host and CPU details for the file header. Anything that can't be determined is left out.
+fn host_metadata() -> Vec<(&'static str, String)> {
+    let mut metadata = vec![
+        ("os", std::env::consts::OS.to_string()),
+        ("arch", std::env::consts::ARCH.to_string()),
+    ];
+    let host = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok());
+    if let Some(host) = host {
+        metadata.push(("host", host.trim().to_string()));
+    }
+    let cpu = std::fs::read_to_string("/proc/cpuinfo").ok().and_then(|info| {
+        info.lines()
+            .find(|line| line.starts_with("model name"))
+            .and_then(|line| line.split(':').nth(1))
+            .map(|model| model.trim().to_string())
+    });
+    if let Some(cpu) = cpu {
+        metadata.push(("cpu", cpu));
+    }
+    if let Ok(cpus) = std::thread::available_parallelism() {
+        metadata.push(("cpus", cpus.to_string()));
+    }
+    metadata.push(("pid", std::process::id().to_string()));
+    metadata.push(("clock", ActiveClock::name().to_string()));
+    metadata
+}
+
+/// Fixed size part of a trace file's header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceFileHeader {
+    pub version: u16,
+    /// Size in bytes of each trace in the file.
+    pub record_size: u32,
+    /// Which optional fields each trace includes, e.g. [`FIELD_CPU_ID`].
+    pub fields: u32,
+    /// As measured by [`ticks_per_ns`] on the machine that wrote the file.
+    pub ticks_per_ns: f64,
+    /// Wall clock when the first thread recorded a trace, in nanoseconds since the unix epoch.
+    pub start_unix_ns: u64,
+    /// Reading of the clock traces were timed with (recorded in metadata as "clock") at the same moment as `start_unix_ns`.
+    pub start_ticks: u64,
+}
+
+impl TraceFileHeader {
+    /// Converts a tick count from this file (e.g. a trace's start) to nanoseconds since the unix epoch.
+    pub fn ticks_to_unix_ns(&self, ticks: u64) -> u64 {
+        let since_start = ticks.wrapping_sub(self.start_ticks) as i64 as f64 / self.ticks_per_ns;
+        (self.start_unix_ns as f64 + since_start) as u64
+    }
+}
+
+/// One thread's section of a trace file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThreadTraces {
+    pub thread_id: u64,
+    pub thread_name: Option<String>,
+    /// Number of traces ever recorded by the thread.
+    pub recorded: u64,
+    /// Number of those traces that were overwritten before the file was written.
+    pub overwritten: u64,
+    /// Number of traces that weren't recorded because the buffer was full with [`FullPolicy::Stop`](crate::FullPolicy::Stop).
+    pub dropped: u64,
+    /// Oldest to newest.
+    pub traces: Vec<Trace>,
+}
+
+/// The contents of a file written by [`write_trace_file`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceFile {
+    pub header: TraceFileHeader,
+    /// Host and CPU details of the machine that wrote the file, e.g. ("host", "myhostname").
+    pub metadata: Vec<(String, String)>,
+    pub tag_names: BTreeMap<u64, String>,
+    pub threads: Vec<ThreadTraces>,
+}
+
+impl TraceFile {
+    /// Reads an entire trace file into memory.
+    /// Files written on a machine with the other byte order are converted.
+    /// Use [`TraceReader`](crate::TraceReader) instead to stream traces without holding them all in memory.
+    pub fn read(reader: &mut impl Read) -> Result<TraceFile> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != FILE_MAGIC {
+            return Err(invalid("not a tsc-trace file"));
+        }
+        let mut input = Input::new(reader, vec![]);
+        let Preamble {
+            header,
+            metadata,
+            tag_names,
+            thread_count,
+        } = input.preamble()?;
+
+        let mut threads = vec![];
+        let mut record = vec![0; header.record_size as usize];
+        for _ in 0..thread_count {
+            let section = input.section()?;
+            // don't trust the count with a huge allocation, the traces that follow will grow it if needed
+            let mut traces = Vec::with_capacity(section.trace_count.min(1 << 20) as usize);
+            for _ in 0..section.trace_count {
+                input.read_exact(&mut record)?;
+                traces.push(input.trace(&record));
+            }
+            threads.push(ThreadTraces {
+                thread_id: section.thread_id,
+                thread_name: section.thread_name,
+                recorded: section.recorded,
+                overwritten: section.overwritten,
+                dropped: section.dropped,
+                traces,
+            });
+        }
+
+        Ok(TraceFile {
+            header,
+            metadata,
+            tag_names,
+            threads,
+        })
+    }
+}
+
+/// Everything in a trace file before the first thread's section.
+pub(crate) struct Preamble {
+    pub(crate) header: TraceFileHeader,
+    pub(crate) metadata: Vec<(String, String)>,
+    pub(crate) tag_names: BTreeMap<u64, String>,
+    pub(crate) thread_count: u32,
+}
+
+/// The fields at the start of a thread's section, before its traces.
+pub(crate) struct Section {
+    pub(crate) thread_id: u64,
+    pub(crate) thread_name: Option<String>,
+    pub(crate) recorded: u64,
+    pub(crate) overwritten: u64,
+    pub(crate) dropped: u64,
+    pub(crate) trace_count: u64,
+}
+
+/// Size in bytes of a trace with the given optional fields, ignoring any unknown to this build.
+pub(crate) fn fields_size(fields: u32) -> u32 {
+    field_offset(fields, u32::MAX) as u32
+}
+
+/// Offset in bytes of `field` in a trace with the given optional fields.
+#[cfg_attr(
+    not(any(feature = "cpu_id", feature = "nesting", feature = "thread_id", feature = "payload")),
+    allow(dead_code)
+)]
+fn field_offset(fields: u32, field: u32) -> usize {
+    let before: u32 = FIELD_SIZES
+        .iter()
+        .filter(|&&(bit, _)| bit < field && fields & bit != 0)
+        .map(|&(_, size)| size)
+        .sum();
+    24 + before as usize
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+struct Output<'a, W: Write>(&'a mut W);
+
+impl<W: Write> Output<'_, W> {
+    fn u16(&mut self, n: u16) -> Result<()> {
+        self.0.write_all(&n.to_ne_bytes())
+    }
+
+    fn u32(&mut self, n: u32) -> Result<()> {
+        self.0.write_all(&n.to_ne_bytes())
+    }
+
+    fn u64(&mut self, n: u64) -> Result<()> {
+        self.0.write_all(&n.to_ne_bytes())
+    }
+
+    fn str(&mut self, s: &str) -> Result<()> {
+        self.u32(s.len() as u32)?;
+        self.0.write_all(s.as_bytes())
+    }
+}
+
+/// Decodes a trace file, or bare traces, from a reader.
+pub(crate) struct Input<R: Read> {
+    reader: R,
+    /// bytes already read from `reader`, e.g. while checking for the magic, to be read again first
+    pending: Vec<u8>,
+    /// true if the file's byte order differs from this machine's
+    swap: bool,
+    /// optional fields in each record
+    fields: u32,
+}
+
+impl<R: Read> Input<R> {
+    pub(crate) fn new(reader: R, pending: Vec<u8>) -> Self {
+        Input {
+            reader,
+            pending,
+            swap: false,
+            fields: FIELDS,
+        }
+    }
+
+    pub(crate) fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        let n = self.pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        self.reader.read_exact(&mut buf[n..])
+    }
+
+    /// Like `read_exact`, but returns false rather than an error if the input was already at its end.
+    pub(crate) fn read_exact_or_end(&mut self, buf: &mut [u8]) -> Result<bool> {
+        let mut filled = self.pending.len().min(buf.len());
+        buf[..filled].copy_from_slice(&self.pending[..filled]);
+        self.pending.drain(..filled);
+        while filled < buf.len() {
+            match self.reader.read(&mut buf[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    pub(crate) fn set_fields(&mut self, fields: u32) {
+        self.fields = fields;
+    }
+
+    /// Reads everything after the magic, up to the first thread's section.
+    pub(crate) fn preamble(&mut self) -> Result<Preamble> {
+        self.swap = match self.u16()? {
+            ENDIANNESS => false,
+            e if e == ENDIANNESS.swap_bytes() => true,
+            _ => return Err(invalid("unrecognized byte order")),
+        };
+        let version = self.u16()?;
+        if version != FILE_VERSION {
+            return Err(invalid("unsupported trace file version"));
+        }
+        let record_size = self.u32()?;
+        self.fields = self.u32()?;
+        let header = TraceFileHeader {
+            version,
+            record_size,
+            fields: self.fields,
+            ticks_per_ns: f64::from_bits(self.u64()?),
+            start_unix_ns: self.u64()?,
+            start_ticks: self.u64()?,
+        };
+        if header.record_size < fields_size(self.fields) {
+            return Err(invalid("trace record size too small"));
+        }
+
+        let mut metadata = vec![];
+        for _ in 0..self.u32()? {
+            metadata.push((self.str()?, self.str()?));
+        }
+
+        let mut tag_names = BTreeMap::new();
+        for _ in 0..self.u32()? {
+            tag_names.insert(self.u64()?, self.str()?);
+        }
+
+        Ok(Preamble {
+            header,
+            metadata,
+            tag_names,
+            thread_count: self.u32()?,
+        })
+    }
+
+    pub(crate) fn section(&mut self) -> Result<Section> {
+        Ok(Section {
+            thread_id: self.u64()?,
+            thread_name: Some(self.str()?).filter(|name| !name.is_empty()),
+            recorded: self.u64()?,
+            overwritten: self.u64()?,
+            dropped: self.u64()?,
+            trace_count: self.u64()?,
+        })
+    }
+
+    /// Decodes a trace from a record that has already been read.
+    /// Optional fields this build has but the record doesn't are left as [`Trace::new`] sets them.
+    pub(crate) fn trace(&self, record: &[u8]) -> Trace {
+        let u64_at = |offset: usize| {
+            let n: u64 = bytemuck::pod_read_unaligned(&record[offset..offset + 8]);
+            if self.swap { n.swap_bytes() } else { n }
+        };
+        #[cfg_attr(
+            not(any(feature = "cpu_id", feature = "nesting", feature = "thread_id", feature = "payload")),
+            allow(unused_mut)
+        )]
+        let mut trace = Trace::new(u64_at(0), u64_at(8), u64_at(16));
+        #[cfg(any(feature = "cpu_id", feature = "thread_id"))]
+        let u32_at = |offset: usize| {
+            let n: u32 = bytemuck::pod_read_unaligned(&record[offset..offset + 4]);
+            if self.swap { n.swap_bytes() } else { n }
+        };
+        #[cfg(feature = "cpu_id")]
+        if self.fields & FIELD_CPU_ID != 0 {
+            let offset = field_offset(self.fields, FIELD_CPU_ID);
+            trace.start_cpu = u32_at(offset);
+            trace.stop_cpu = u32_at(offset + 4);
+        }
+        #[cfg(feature = "nesting")]
+        if self.fields & FIELD_NESTING != 0 {
+            let offset = field_offset(self.fields, FIELD_NESTING);
+            trace.id = u64_at(offset);
+            trace.parent = u64_at(offset + 8);
+            trace.depth = u64_at(offset + 16);
+        }
+        #[cfg(feature = "thread_id")]
+        if self.fields & FIELD_THREAD_ID != 0 {
+            let offset = field_offset(self.fields, FIELD_THREAD_ID);
+            trace.start_thread = u32_at(offset);
+            trace.stop_thread = u32_at(offset + 4);
+        }
+        #[cfg(feature = "payload")]
+        if self.fields & FIELD_PAYLOAD != 0 {
+            let offset = field_offset(self.fields, FIELD_PAYLOAD);
+            trace.args = [u64_at(offset), u64_at(offset + 8)];
+        }
+        trace
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let mut bytes = [0; 2];
+        self.read_exact(&mut bytes)?;
+        let n = u16::from_ne_bytes(bytes);
+        Ok(if self.swap { n.swap_bytes() } else { n })
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        self.read_exact(&mut bytes)?;
+        let n = u32::from_ne_bytes(bytes);
+        Ok(if self.swap { n.swap_bytes() } else { n })
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        self.read_exact(&mut bytes)?;
+        let n = u64::from_ne_bytes(bytes);
+        Ok(if self.swap { n.swap_bytes() } else { n })
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let len = self.u32()?;
+        if len > 1 << 20 {
+            return Err(invalid("trace file string too long"));
+        }
+        let mut bytes = vec![0; len as usize];
+        self.read_exact(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|_| invalid("trace file string is not UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TraceReader, NO_SPAN};
+
+    #[test]
+    #[cfg(not(feature = "off"))]
+    fn round_trip() {
+        let thread_id = std::thread::spawn(|| {
+            crate::register_tag(0xf11e, "round trip");
+            for start in 0..3 {
+                crate::_insert_trace(0xf11e, start, start + 10);
+            }
+            crate::trace_stats().thread_id
+        })
+        .join()
+        .unwrap();
+        let mut bytes = vec![];
+        write_trace_file(&mut bytes).unwrap();
+
+        let file = TraceFile::read(&mut &bytes[..]).unwrap();
+        assert_eq!(file.header.version, FILE_VERSION);
+        assert_eq!(file.header.record_size, RECORD_SIZE);
+        assert_eq!(file.header.fields, FIELDS);
+        assert_eq!(file.tag_names.get(&0xf11e).map(String::as_str), Some("round trip"));
+        assert!(file.metadata.iter().any(|(key, _)| key == "pid"));
+        let thread = file.threads.iter().find(|thread| thread.thread_id == thread_id).unwrap();
+        assert_eq!((thread.recorded, thread.overwritten, thread.dropped), (3, 0, 0));
+        let spans: Vec<_> = thread.traces.iter().map(|trace| (trace.tag, trace.start, trace.stop)).collect();
+        assert_eq!(spans, [(0xf11e, 0, 10), (0xf11e, 1, 11), (0xf11e, 2, 12)]);
+
+        let mut reader = TraceReader::new(&bytes[..]).unwrap().tags(0xf11e..=0xf11e);
+        let mut streamed = vec![];
+        while let Some(trace) = reader.next() {
+            let trace = trace.unwrap();
+            if reader.thread_id() == Some(thread_id) {
+                assert_eq!(reader.thread_stats().map(|stats| stats.recorded), Some(3));
+                streamed.push(trace);
+            }
+        }
+        assert_eq!(streamed, thread.traces);
+    }
+
+    /// Writes integers in the other byte order, as a machine with that order would.
+    struct Swapped(Vec<u8>);
+
+    impl Swapped {
+        fn u16(&mut self, n: u16) {
+            self.0.extend(n.swap_bytes().to_ne_bytes());
+        }
+
+        fn u32(&mut self, n: u32) {
+            self.0.extend(n.swap_bytes().to_ne_bytes());
+        }
+
+        fn u64(&mut self, n: u64) {
+            self.0.extend(n.swap_bytes().to_ne_bytes());
+        }
+
+        fn str(&mut self, s: &str) {
+            self.u32(s.len() as u32);
+            self.0.extend(s.as_bytes());
+        }
+    }
+
+    #[test]
+    fn reads_other_byte_order_and_fields() {
+        let fields = FIELD_CPU_ID | FIELD_PAYLOAD;
+        let mut file = Swapped(FILE_MAGIC.to_vec());
+        file.u16(ENDIANNESS);
+        file.u16(FILE_VERSION);
+        // 8 bytes more than the fields need, as a newer version might write
+        file.u32(fields_size(fields) + 8);
+        file.u32(fields);
+        file.u64(2.5f64.to_bits());
+        file.u64(1_000);
+        file.u64(500);
+        file.u32(1);
+        file.str("host");
+        file.str("elsewhere");
+        file.u32(1);
+        file.u64(7);
+        file.str("seven");
+        file.u32(1);
+        file.u64(4);
+        file.str("worker");
+        file.u64(5);
+        file.u64(3);
+        file.u64(1);
+        file.u64(2);
+        for (start, cpu) in [(600, 1), (700, 2)] {
+            file.u64(7);
+            file.u64(start);
+            file.u64(start + 50);
+            file.u32(cpu);
+            file.u32(cpu + 1);
+            file.u64(start * 2);
+            file.u64(start * 3);
+            file.u64(u64::MAX);
+        }
+
+        let read = TraceFile::read(&mut &file.0[..]).unwrap();
+        assert_eq!(read.header.fields, fields);
+        assert_eq!(read.header.ticks_per_ns, 2.5);
+        assert_eq!(read.header.ticks_to_unix_ns(700), 1_080);
+        assert_eq!(read.metadata, [("host".to_string(), "elsewhere".to_string())]);
+        assert_eq!(read.tag_names.get(&7).map(String::as_str), Some("seven"));
+        let thread = &read.threads[0];
+        assert_eq!(thread.thread_id, 4);
+        assert_eq!(thread.thread_name.as_deref(), Some("worker"));
+        assert_eq!((thread.recorded, thread.overwritten, thread.dropped), (5, 3, 1));
+        let spans: Vec<_> = thread.traces.iter().map(|trace| (trace.tag, trace.start, trace.stop)).collect();
+        assert_eq!(spans, [(7, 600, 650), (7, 700, 750)]);
+        #[cfg(feature = "cpu_id")]
+        assert_eq!((thread.traces[1].start_cpu, thread.traces[1].stop_cpu), (2, 3));
+        #[cfg(feature = "payload")]
+        assert_eq!(thread.traces[1].args, [1400, 2100]);
+        #[cfg(feature = "nesting")]
+        assert_eq!(thread.traces[1].id, NO_SPAN);
+
+        let streamed: Vec<_> = TraceReader::new(&file.0[..]).unwrap().map(Result::unwrap).collect();
+        assert_eq!(streamed, thread.traces);
+    }
+
+    #[test]
+    fn reads_bare_traces_with_other_fields() {
+        let mut bare: Vec<u8> = vec![];
+        for (tag, id, parent, depth) in [(1u64, 0u64, NO_SPAN, 0u64), (2, 1, 0, 1)] {
+            for word in [tag, 100 * tag, 100 * tag + 5, id, parent, depth] {
+                bare.extend(word.to_ne_bytes());
+            }
+        }
+        let traces: Vec<_> = TraceReader::new(&bare[..])
+            .unwrap()
+            .bare_fields(FIELD_NESTING)
+            .map(Result::unwrap)
+            .collect();
+        let spans: Vec<_> = traces.iter().map(|trace| (trace.tag, trace.start, trace.stop)).collect();
+        assert_eq!(spans, [(1, 100, 105), (2, 200, 205)]);
+        #[cfg(feature = "nesting")]
+        assert_eq!((traces[1].id, traces[1].parent, traces[1].depth), (1, 0, 1));
+    }
+}
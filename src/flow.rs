@@ -0,0 +1,169 @@
+//! Flows, which link spans that handle the same thing, e.g. a message produced on one thread and consumed on another.
+
+use crate::{now, RecordKind, Trace};
+
+/// Starts the flow `id` in the innermost span open on this thread.
+/// Exporters draw an arrow from that span to the span of each later [`flow_step`] with the same id.
+///
+/// It's recorded as a trace of kind [`RecordKind::FlowStart`] at the current time,
+/// and attached to the span it was recorded in when exported, as worked out by [`FlowSpans`].
+/// Ids are chosen by the caller, e.g. a message's sequence number, and shouldn't be reused by another flow in the same capture.
+pub fn flow_start(id: u64) {
+    record(RecordKind::FlowStart, id);
+}
+
+/// Continues the flow `id` in the innermost span open on this thread, which can be on a different thread than the previous step.
+pub fn flow_step(id: u64) {
+    record(RecordKind::FlowStep, id);
+}
+
+fn record(kind: RecordKind, id: u64) {
+    if cfg!(feature = "off") {
+        return;
+    }
+    crate::insert_record(kind.tag_bits(), now(), id);
+}
+
+/// Works out which span each flow was recorded in, from one thread's traces oldest to newest, as the exporters see them.
+///
+/// With the `"nesting"` feature, that's the span whose id is the flow's parent.
+/// Otherwise it's the innermost span that had started before the flow was recorded and stopped after,
+/// or, if there's none, one that started at the same time, so a flow recorded as one span stops and the next starts
+/// goes on the span that was open.
+/// Spans stop after the flows recorded in them, so each is known by the time that span is pushed.
+#[derive(Default)]
+pub struct FlowSpans {
+    /// flows not yet attached, oldest first, each with the index of a span that started at the same time
+    pending: Vec<(Trace, Option<usize>)>,
+    /// number of traces pushed
+    pushed: usize,
+}
+
+impl FlowSpans {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the thread's next trace. If it's a span, returns the flows recorded in it, oldest first.
+    pub fn push(&mut self, trace: &Trace) -> impl Iterator<Item = Trace> + '_ {
+        let index = self.pushed;
+        self.pushed += 1;
+        let claimed = match trace.kind() {
+            RecordKind::FlowStart | RecordKind::FlowStep => {
+                self.pending.push((*trace, None));
+                self.pending.len()
+            }
+            RecordKind::Span if trace.stop >= trace.start => {
+                // flows are pending oldest first, and those in this span were recorded after any still waiting for outer spans
+                let mut claimed = self.pending.len();
+                while claimed > 0 && in_span(&self.pending[claimed - 1].0, trace) {
+                    claimed -= 1;
+                }
+                for (flow, started_then) in self.pending[..claimed].iter_mut().rev() {
+                    if flow.start < trace.start {
+                        break;
+                    }
+                    if !has_parent(flow) && flow.start == trace.start && started_then.is_none() {
+                        *started_then = Some(index);
+                    }
+                }
+                claimed
+            }
+            _ => self.pending.len(),
+        };
+        self.pending.drain(claimed..).map(|(flow, _)| flow)
+    }
+
+    /// Returns the flows no span claimed, oldest first, each with the index (counting every trace pushed)
+    /// of a span that started as it was recorded, if there was one. Call this after the thread's last trace.
+    pub fn finish(&mut self) -> impl Iterator<Item = (Trace, Option<usize>)> + '_ {
+        self.pushed = 0;
+        self.pending.drain(..)
+    }
+}
+
+/// Whether `flow` was recorded with the id of the span it's in.
+#[cfg_attr(not(feature = "nesting"), allow(unused_variables))]
+fn has_parent(flow: &Trace) -> bool {
+    #[cfg(feature = "nesting")]
+    {
+        flow.parent != crate::NO_SPAN
+    }
+    #[cfg(not(feature = "nesting"))]
+    {
+        false
+    }
+}
+
+/// Whether `flow` was recorded in `span`, assuming no span pushed since the flow claimed it.
+fn in_span(flow: &Trace, span: &Trace) -> bool {
+    #[cfg(feature = "nesting")]
+    if has_parent(flow) {
+        return flow.parent == span.id;
+    }
+    span.start < flow.start && flow.start <= span.stop
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(time: u64, id: u64) -> Trace {
+        Trace::new(RecordKind::FlowStart.tag_bits(), time, id)
+    }
+
+    /// Ids of the flows each trace claimed, in the order they were recorded,
+    /// then the unclaimed flows' ids, each with the span that started as it was recorded.
+    type Claims = (Vec<Vec<u64>>, Vec<(u64, Option<usize>)>);
+
+    fn claims(traces: &[Trace]) -> Claims {
+        let mut flow_spans = FlowSpans::new();
+        let claimed = traces
+            .iter()
+            .map(|trace| flow_spans.push(trace).map(|flow| flow.stop).collect())
+            .collect();
+        let unclaimed = flow_spans.finish().map(|(flow, span)| (flow.stop, span)).collect();
+        (claimed, unclaimed)
+    }
+
+    #[test]
+    fn flow_as_one_span_stops_and_the_next_starts_goes_on_the_first() {
+        let traces = [flow(5, 77), Trace::new(1, 0, 5), Trace::new(2, 5, 10)];
+        assert_eq!(claims(&traces), (vec![vec![], vec![77], vec![]], vec![]));
+    }
+
+    #[test]
+    fn innermost_span_claims_flows_and_outer_ones_the_rest() {
+        let traces = [
+            flow(2, 1),
+            flow(4, 2),
+            Trace::new(2, 3, 6),
+            flow(8, 3),
+            Trace::new(1, 0, 10),
+        ];
+        assert_eq!(claims(&traces), (vec![vec![], vec![], vec![2], vec![], vec![1, 3]], vec![]));
+    }
+
+    #[test]
+    fn flow_in_no_span_that_had_started_goes_on_one_starting_then() {
+        let traces = [flow(5, 77), Trace::new(2, 5, 10), flow(20, 78)];
+        assert_eq!(claims(&traces), (vec![vec![]; 3], vec![(77, Some(1)), (78, None)]));
+    }
+
+    #[test]
+    #[cfg(feature = "nesting")]
+    fn recorded_parent_settles_equal_times() {
+        let with_ids = |mut trace: Trace, id, parent| {
+            trace.id = id;
+            trace.parent = parent;
+            trace
+        };
+        // started in the inner span, at the same tick it started
+        let traces = [
+            with_ids(flow(5, 77), 2, 1),
+            with_ids(Trace::new(2, 5, 8), 1, 0),
+            with_ids(Trace::new(1, 0, 10), 0, crate::NO_SPAN),
+        ];
+        assert_eq!(claims(&traces), (vec![vec![], vec![77], vec![]], vec![]));
+    }
+}
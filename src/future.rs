@@ -0,0 +1,73 @@
+//! Tracing of futures, one span per poll, since a span held across an `.await` would include the time spent suspended.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::{now, TraceSpan};
+
+/// Set in the tag of the span [`Traced::with_lifetime`] records from first poll to completion,
+/// telling it apart from the spans of each poll, which have the tag as given.
+/// Like the record kind, it's above [`TAG_MASK`](crate::TAG_MASK), so [`Trace::base_tag`](crate::Trace::base_tag) leaves it out.
+pub const LIFETIME_FLAG: u64 = 1 << 62;
+
+/// A future that records a trace of each poll of the future it wraps, made by [`TraceFutureExt::traced`].
+///
+/// Each poll is a span like one from `trace_span!`, so it nests under whatever span is open on the polling thread.
+/// With the `"off"` feature, it only polls the inner future.
+#[must_use = "futures do nothing unless polled"]
+pub struct Traced<F> {
+    future: F,
+    tag: u64,
+    lifetime: bool,
+    /// when the first poll started, once it has, if recording the lifetime
+    first_poll: Option<u64>,
+}
+
+impl<F> Traced<F> {
+    /// Also records a span from the start of the first poll to the end of the one that completes the future,
+    /// tagged `tag | LIFETIME_FLAG`. It's recorded by the thread that completes the future, which may not be the one that first polled it.
+    /// Nothing is recorded for a future dropped before completing.
+    pub fn with_lifetime(mut self) -> Self {
+        self.lifetime = true;
+        self
+    }
+}
+
+impl<F: Future> Future for Traced<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // Safety: the future is never moved out of self, and is pinned whenever self is.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if cfg!(feature = "off") {
+            return future.poll(cx);
+        }
+        let span = TraceSpan::new(this.tag);
+        if this.lifetime && this.first_poll.is_none() {
+            this.first_poll = Some(span.start());
+        }
+        let poll = future.poll(cx);
+        drop(span);
+        if let (Poll::Ready(_), Some(start)) = (&poll, this.first_poll) {
+            crate::insert_record(this.tag | LIFETIME_FLAG, start, now());
+        }
+        poll
+    }
+}
+
+/// Adds [`traced`](TraceFutureExt::traced) to every future.
+pub trait TraceFutureExt: Future + Sized {
+    /// Wraps the future so each poll records a trace with `tag`, e.g. `fetch(url).traced(Traces::Fetch as u64).await`.
+    fn traced(self, tag: u64) -> Traced<Self> {
+        Traced {
+            future: self,
+            tag: crate::user_tag(tag),
+            lifetime: false,
+            first_poll: None,
+        }
+    }
+}
+
+impl<F: Future> TraceFutureExt for F {}
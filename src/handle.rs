@@ -0,0 +1,110 @@
+//! Spans started and ended explicitly, rather than by scope, e.g. from one callback to another, or on different threads.
+
+#[cfg(feature = "thread_id")]
+use crate::registry;
+#[cfg(feature = "cpu_id")]
+use crate::NO_CPU;
+#[cfg(feature = "thread_id")]
+use crate::NO_THREAD;
+use crate::{ActiveClock, ArgIndex, ClockSource, Trace};
+
+/// A span started by [`begin`], recorded when [`end`](SpanHandle::end) is called.
+///
+/// It can be sent to another thread and ended there, in which case it's recorded by that thread,
+/// and with the `"thread_id"` feature, both threads are recorded in the trace.
+/// Dropping it without calling `end` is the same as [`cancel`](SpanHandle::cancel).
+#[must_use = "the span is only recorded when end is called"]
+pub struct SpanHandle {
+    tag: u64,
+    start: u64,
+    #[cfg(feature = "cpu_id")]
+    start_cpu: u32,
+    #[cfg(feature = "thread_id")]
+    start_thread: u32,
+    #[cfg(feature = "payload")]
+    args: [u64; 2],
+}
+
+/// Starts a span with the given tag, which lasts until [`SpanHandle::end`] is called.
+///
+/// Unlike `trace_span!`, the span isn't tied to a scope or thread.
+/// With the `"nesting"` feature it isn't part of either thread's stack of spans, so it's recorded at the top level.
+/// With the `"off"` feature, nothing is recorded, and neither the clock nor the thread's id is read.
+pub fn begin(tag: u64) -> SpanHandle {
+    if cfg!(feature = "off") {
+        return SpanHandle {
+            tag,
+            start: 0,
+            #[cfg(feature = "cpu_id")]
+            start_cpu: NO_CPU,
+            #[cfg(feature = "thread_id")]
+            start_thread: NO_THREAD,
+            #[cfg(feature = "payload")]
+            args: [crate::NO_ARG; 2],
+        };
+    }
+    #[cfg(not(feature = "cpu_id"))]
+    let start = ActiveClock::now();
+    #[cfg(feature = "cpu_id")]
+    let (start, start_cpu) = ActiveClock::now_with_cpu();
+    SpanHandle {
+        tag: crate::user_tag(tag),
+        start,
+        #[cfg(feature = "cpu_id")]
+        start_cpu,
+        #[cfg(feature = "thread_id")]
+        start_thread: registry::current_thread_id() as u32,
+        #[cfg(feature = "payload")]
+        args: [crate::NO_ARG; 2],
+    }
+}
+
+impl SpanHandle {
+    /// Sets one of the two values recorded with the span, like those given to `trace_span!(tag, arg0, arg1)`,
+    /// e.g. once a request's size is known.
+    /// Without the `"payload"` feature, nothing is stored.
+    #[cfg_attr(not(feature = "payload"), allow(unused_variables))]
+    pub fn set_arg(&mut self, index: ArgIndex, value: u64) {
+        #[cfg(feature = "payload")]
+        {
+            self.args[index as usize] = value;
+        }
+    }
+
+    /// Ends the span, recording it in the current thread's traces.
+    pub fn end(self) {
+        if cfg!(feature = "off") {
+            return;
+        }
+        #[cfg(not(feature = "cpu_id"))]
+        let stop = ActiveClock::now();
+        #[cfg(feature = "cpu_id")]
+        let (stop, stop_cpu) = ActiveClock::now_with_cpu();
+        #[cfg_attr(not(any(feature = "cpu_id", feature = "nesting", feature = "payload")), allow(unused_mut))]
+        let mut trace = Trace::new(self.tag, self.start, stop);
+        #[cfg(feature = "cpu_id")]
+        {
+            trace.start_cpu = self.start_cpu;
+            trace.stop_cpu = stop_cpu;
+        }
+        #[cfg(feature = "nesting")]
+        {
+            trace.id = crate::nesting::leaf().id;
+        }
+        #[cfg(feature = "payload")]
+        {
+            trace.args = self.args;
+        }
+        crate::registry::with_current_entry(|_thread_id, buffer| {
+            #[cfg(feature = "thread_id")]
+            {
+                trace.start_thread = self.start_thread;
+                trace.stop_thread = _thread_id as u32;
+            }
+            buffer.insert(trace)
+        })
+    }
+
+    /// Discards the span without recording anything.
+    pub fn cancel(self) {}
+}
@@ -0,0 +1,66 @@
+//! A [`tracing_subscriber::Layer`] that records `tracing` spans in the thread local buffers, with the `"tracing"` feature.
+
+use std::cell::RefCell;
+
+use tracing_core::span::Id;
+use tracing_core::subscriber::{Interest, Subscriber};
+use tracing_core::Metadata;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::{register_tag, tags::path_tag, TraceSpan};
+
+thread_local! {
+    /// spans entered on this thread and not yet exited, innermost last
+    static ENTERED: RefCell<Vec<(Id, TraceSpan)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Records each time a `tracing` span is entered and exited as a trace, as if by `trace_span!`,
+/// so code instrumented with `#[instrument]` or `span!` is traced without a second set of macros.
+///
+/// ```ignore
+/// use tracing_subscriber::prelude::*;
+/// tracing_subscriber::registry().with(tsc_trace::TscTraceLayer).init();
+/// ```
+///
+/// A span's tag is [`name_tag`](crate::name_tag) of `"{target}::{name}"`, which for `#[instrument]` is the function's path,
+/// the same tag `#[trace]` would give it. That is registered as the tag's name the first time `tracing` sees the callsite.
+/// Spans entered more than once, e.g. in a future that's polled repeatedly, record a trace each time.
+/// Events, and the fields of spans, are ignored.
+pub struct TscTraceLayer;
+
+impl<S> Layer<S> for TscTraceLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if metadata.is_span() {
+            register_tag(
+                path_tag(metadata.target(), metadata.name()),
+                format!("{}::{}", metadata.target(), metadata.name()),
+            );
+        }
+        Interest::always()
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if cfg!(feature = "off") {
+            return;
+        }
+        if let Some(metadata) = ctx.metadata(id) {
+            let span = TraceSpan::new(path_tag(metadata.target(), metadata.name()));
+            ENTERED.with(|entered| entered.borrow_mut().push((id.clone(), span)));
+        }
+    }
+
+    fn on_exit(&self, id: &Id, _ctx: Context<'_, S>) {
+        // spans normally exit innermost first, but needn't, e.g. guards dropped out of order
+        let span = ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            let i = entered.iter().rposition(|(entered_id, _)| entered_id == id)?;
+            Some(entered.remove(i).1)
+        });
+        // stops the span, outside the borrow
+        drop(span);
+    }
+}
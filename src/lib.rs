@@ -1,81 +1,417 @@
 #![doc = include_str!("../README.md")]
 
-use std::cell::{Cell, RefCell};
-use std::io::{Result, Write};
+#[cfg(target_arch = "aarch64")]
 use std::arch::asm;
+use bytemuck::{Pod, Zeroable};
+use std::io::{Result, Write};
+use std::sync::atomic::{AtomicU8, Ordering::Relaxed};
+use std::sync::{Mutex, OnceLock, PoisonError};
 
-/// capacity in number of traces per thread
-#[cfg(all(not(feature = "off"), feature = "capacity_1_million"))]
-pub const TSC_TRACE_CAPACITY: usize = 1_000_000;
+/// Default capacity in number of traces per thread, set by the `capacity_*_million` features.
+/// If several are enabled (e.g. by different crates in a dependency graph), the largest wins.
+/// [`init`] or the `TSC_TRACE_CAPACITY` environment variable override it at runtime.
+pub const TSC_TRACE_CAPACITY: usize = if cfg!(feature = "off") {
+    0
+} else if cfg!(feature = "capacity_64_million") {
+    64_000_000
+} else if cfg!(feature = "capacity_32_million") {
+    32_000_000
+} else if cfg!(feature = "capacity_16_million") {
+    16_000_000
+} else if cfg!(feature = "capacity_8_million") {
+    8_000_000
+} else {
+    1_000_000
+};
 
-/// capacity in number of traces per thread
-#[cfg(all(not(feature = "off"), feature = "capacity_8_million"))]
-pub const TSC_TRACE_CAPACITY: usize = 8_000_000;
+/// Environment variable read for the capacity, in number of traces per thread, if [`init`] wasn't called first.
+pub const CAPACITY_ENV_VAR: &str = "TSC_TRACE_CAPACITY";
+
+static RUNTIME_CAPACITY: OnceLock<usize> = OnceLock::new();
+
+/// Sets the capacity in number of traces per thread, overriding [`TSC_TRACE_CAPACITY`] and [`CAPACITY_ENV_VAR`].
+///
+/// Must be called before the first trace is recorded, since every thread's buffer is that size.
+/// Returns false, and changes nothing, if the capacity was already fixed by an earlier call or trace.
+/// With the `"const_array"` or `"off"` features, buffers are sized at compile time, so this always returns false.
+pub fn init(capacity: usize) -> bool {
+    !cfg!(any(feature = "const_array", feature = "off")) && RUNTIME_CAPACITY.set(capacity).is_ok()
+}
 
-/// capacity in number of traces per thread
-#[cfg(all(not(feature = "off"), feature = "capacity_16_million"))]
-pub const TSC_TRACE_CAPACITY: usize = 16_000_000;
+/// Capacity in number of traces per thread.
+/// Fixed on first call (which recording the first trace does) to the value given to [`init`],
+/// or else [`CAPACITY_ENV_VAR`] if that's set to a number, or else [`TSC_TRACE_CAPACITY`].
+pub fn capacity() -> usize {
+    if cfg!(any(feature = "const_array", feature = "off")) {
+        return TSC_TRACE_CAPACITY;
+    }
+    *RUNTIME_CAPACITY.get_or_init(|| {
+        std::env::var(CAPACITY_ENV_VAR)
+            .ok()
+            .and_then(|capacity| capacity.trim().replace('_', "").parse().ok())
+            .unwrap_or(TSC_TRACE_CAPACITY)
+    })
+}
 
-/// capacity in number of traces per thread
-#[cfg(all(not(feature = "off"), feature = "capacity_32_million"))]
-pub const TSC_TRACE_CAPACITY: usize = 32_000_000;
+/// What a thread does with a new trace when its buffer is full, set by [`set_full_policy`].
+#[derive(Clone, Copy, Debug)]
+pub enum FullPolicy {
+    /// Overwrite the oldest trace, so the buffer always holds the newest. The default.
+    Wrap,
+    /// Stop recording, keeping the oldest traces. New ones are counted in [`TraceStats::dropped`].
+    Stop,
+    /// Call the function with the buffer's traces, oldest to newest, then empty it and keep recording.
+    /// It's called on the thread whose buffer filled, in the middle of recording a trace, so it should be quick,
+    /// e.g. copying the traces somewhere to be written by another thread.
+    Flush(fn(&[Trace])),
+}
 
-/// capacity in number of traces per thread
-#[cfg(all(not(feature = "off"), feature = "capacity_64_million"))]
-pub const TSC_TRACE_CAPACITY: usize = 64_000_000;
+static FULL_POLICY: AtomicU8 = AtomicU8::new(0);
+/// callback of [`FullPolicy::Flush`]
+type FlushFn = fn(&[Trace]);
 
-/// capacity in number of traces per thread
-#[cfg(feature = "off")]
-pub const TSC_TRACE_CAPACITY: usize = 0;
+static FLUSH: Mutex<Option<FlushFn>> = Mutex::new(None);
 
-/// capacity in number of traces per thread
-#[cfg(all(
-    not(feature = "off"),
-    not(feature = "capacity_1_million"),
-    not(feature = "capacity_8_million"),
-    not(feature = "capacity_16_million"),
-    not(feature = "capacity_32_million"),
-    not(feature = "capacity_64_million")
-))]
-pub const TSC_TRACE_CAPACITY: usize = 1_000_000;
+/// Sets what every thread does when its buffer is full, from then on. See [`FullPolicy`].
+pub fn set_full_policy(policy: FullPolicy) {
+    let kind = match policy {
+        FullPolicy::Wrap => 0,
+        FullPolicy::Stop => 1,
+        FullPolicy::Flush(flush) => {
+            *FLUSH.lock().unwrap_or_else(PoisonError::into_inner) = Some(flush);
+            2
+        }
+    };
+    FULL_POLICY.store(kind, Relaxed);
+}
 
-const CAPACITY: usize = TSC_TRACE_CAPACITY * 3;
+/// What threads do when their buffer is full, as last given to [`set_full_policy`].
+pub fn full_policy() -> FullPolicy {
+    match FULL_POLICY.load(Relaxed) {
+        1 => FullPolicy::Stop,
+        2 => match *FLUSH.lock().unwrap_or_else(PoisonError::into_inner) {
+            Some(flush) => FullPolicy::Flush(flush),
+            None => FullPolicy::Wrap,
+        },
+        _ => FullPolicy::Wrap,
+    }
+}
 
+/// capacity in number of u64 words per thread, with the `"const_array"` feature
 #[cfg(feature = "const_array")]
-thread_local! {
-    static TSC_TRACE_SPANS: RefCell<[u64; CAPACITY]> = const { RefCell::new([0; CAPACITY]) };
-    static TSC_TRACE_INDEX: Cell<usize> = const { Cell::new(0) };
+const CAPACITY: usize = TSC_TRACE_CAPACITY * TRACE_WORDS;
+
+mod analysis;
+mod calibration;
+mod chrome;
+pub mod clock;
+pub mod file;
+mod flow;
+mod future;
+mod handle;
+#[cfg(feature = "tracing")]
+mod layer;
+#[cfg(feature = "nesting")]
+mod nesting;
+mod perfetto;
+mod reader;
+mod registry;
+mod tags;
+
+pub use analysis::{all_threads_tag_costs, tag_costs, tag_costs_from, TagCost};
+pub use calibration::{calibrate, ticks_per_ns, ticks_to_ns, DEFAULT_CALIBRATION};
+pub use clock::{now, set_custom_clock, ActiveClock, ClockSource, MockClock};
+pub use chrome::{write_all_threads_chrome_json, write_chrome_json_from, write_traces_chrome_json};
+pub use file::{write_trace_file, TraceFile};
+pub use flow::{flow_start, flow_step, FlowSpans};
+pub use future::{TraceFutureExt, Traced, LIFETIME_FLAG};
+pub use handle::{begin, SpanHandle};
+#[cfg(feature = "tracing")]
+pub use layer::TscTraceLayer;
+pub use perfetto::{write_all_threads_perfetto, write_perfetto_from, write_traces_perfetto};
+pub use reader::TraceReader;
+pub use tags::{_register_tag_once, name_tag, register_tag, TraceTags};
+pub use tsc_trace_macros::{trace, TraceTags};
+
+/// A single trace, as recorded by [`TraceSpan`] or `insert_trace!`.
+/// Has the same layout as each trace written by [`write_traces_binary`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Pod, Zeroable)]
+#[repr(C)]
+pub struct Trace {
+    pub tag: u64,
+    pub start: u64,
+    pub stop: u64,
+    /// CPU the span started on, from [`ClockSource::now_with_cpu`] (rdtscp on x86), or [`NO_CPU`] if unknown (e.g. from `insert_trace!`).
+    #[cfg(feature = "cpu_id")]
+    pub start_cpu: u32,
+    /// CPU the span stopped on, as for `start_cpu`.
+    #[cfg(feature = "cpu_id")]
+    pub stop_cpu: u32,
+    /// Number of spans the thread had started before this one, or [`NO_SPAN`] if unknown.
+    #[cfg(feature = "nesting")]
+    pub id: u64,
+    /// `id` of the innermost span that was open on the same thread when this one started, or [`NO_SPAN`] at the top level.
+    #[cfg(feature = "nesting")]
+    pub parent: u64,
+    /// Number of spans open on the same thread when this one started, 0 at the top level.
+    #[cfg(feature = "nesting")]
+    pub depth: u64,
+    /// Thread id (as in [`write_all_threads_csv`]) of the thread that started a span from [`begin`], or [`NO_THREAD`] for other traces,
+    /// which start and stop on the thread that recorded them.
+    #[cfg(feature = "thread_id")]
+    pub start_thread: u32,
+    /// Thread id of the thread that ended a span from [`begin`], as for `start_thread`.
+    #[cfg(feature = "thread_id")]
+    pub stop_thread: u32,
+    /// Values given to `trace_span!(tag, arg0, arg1)` (or `set_arg`), e.g. a request's size, or [`NO_ARG`] where none was given.
+    #[cfg(feature = "payload")]
+    pub args: [u64; 2],
 }
 
-#[cfg(not(feature = "const_array"))]
-thread_local! {
-    static TSC_TRACE_SPANS: RefCell<Vec<u64>> = RefCell::new(Vec::with_capacity(CAPACITY));
-    static TSC_TRACE_INDEX: Cell<usize> = const { Cell::new(0) };
+/// Number of u64 words in each [`Trace`], as stored in the thread local buffers.
+pub(crate) const TRACE_WORDS: usize = std::mem::size_of::<Trace>() / 8;
+
+/// CPU id of a trace whose CPU isn't known.
+pub const NO_CPU: u32 = u32::MAX;
+
+/// Span id of a trace with no parent, or whose id isn't known.
+pub const NO_SPAN: u64 = u64::MAX;
+
+/// Thread id of a trace whose thread isn't recorded.
+pub const NO_THREAD: u32 = u32::MAX;
+
+/// Payload value of a trace that wasn't given one.
+pub const NO_ARG: u64 = u64::MAX;
+
+/// Which of a span's two payload values to set, e.g. with [`SpanHandle::set_arg`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgIndex {
+    /// The first, arg0 in exports.
+    Arg0 = 0,
+    /// The second, arg1 in exports.
+    Arg1 = 1,
+}
+
+/// Bits of [`Trace::tag`] that hold the tag itself.
+/// The bits above hold the [`RecordKind`] and flags like [`LIFETIME_FLAG`], so tags given to the macros must fit in these.
+/// Larger tags panic in debug builds, and have the bits above cut off in release builds.
+pub const TAG_MASK: u64 = (1 << KIND_SHIFT) - 1;
+
+/// Checks a tag given to a macro or function fits in [`TAG_MASK`], cutting off any bits above it in release builds.
+#[inline(always)]
+pub(crate) fn user_tag(tag: u64) -> u64 {
+    debug_assert!(tag <= TAG_MASK, "tsc-trace tags must fit in TAG_MASK (56 bits), got {tag:#x}");
+    tag & TAG_MASK
+}
+
+const KIND_SHIFT: u32 = 56;
+const KIND_BITS: u64 = 0x3f;
+
+/// What a [`Trace`] records, which is kept in bits 56 to 61 of its tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordKind {
+    /// A span from `start` to `stop`.
+    Span,
+    /// The start of a flow, from [`flow_start`]: `start` is when, and `stop` is the flow's id.
+    FlowStart,
+    /// A later step of a flow, from [`flow_step`], as for `FlowStart`.
+    FlowStep,
+    /// A point in time, from `trace_instant!`: `start` and `stop` are both when.
+    Instant,
+    /// A sample of a counter, from `trace_counter!`: `start` is when, and `stop` is the value.
+    Counter,
+    /// A kind this build doesn't know, e.g. from a file written by a newer version.
+    Unknown(u8),
+}
+
+impl RecordKind {
+    /// The bits of a tag that say a record is of this kind.
+    pub(crate) const fn tag_bits(self) -> u64 {
+        let kind = match self {
+            RecordKind::Span => 0,
+            RecordKind::FlowStart => 1,
+            RecordKind::FlowStep => 2,
+            RecordKind::Instant => 3,
+            RecordKind::Counter => 4,
+            RecordKind::Unknown(kind) => kind as u64,
+        };
+        (kind & KIND_BITS) << KIND_SHIFT
+    }
+}
+
+impl Trace {
+    /// A trace with nothing but a tag, start and stop known.
+    pub(crate) fn new(tag: u64, start: u64, stop: u64) -> Self {
+        Trace {
+            tag,
+            start,
+            stop,
+            #[cfg(feature = "cpu_id")]
+            start_cpu: NO_CPU,
+            #[cfg(feature = "cpu_id")]
+            stop_cpu: NO_CPU,
+            #[cfg(feature = "nesting")]
+            id: NO_SPAN,
+            #[cfg(feature = "nesting")]
+            parent: NO_SPAN,
+            #[cfg(feature = "nesting")]
+            depth: 0,
+            #[cfg(feature = "thread_id")]
+            start_thread: NO_THREAD,
+            #[cfg(feature = "thread_id")]
+            stop_thread: NO_THREAD,
+            #[cfg(feature = "payload")]
+            args: [NO_ARG; 2],
+        }
+    }
+
+    /// True if the span started and stopped on different CPUs, so its timestamps came from two different counters.
+    #[cfg(feature = "cpu_id")]
+    pub fn migrated(&self) -> bool {
+        self.start_cpu != self.stop_cpu && self.start_cpu != NO_CPU && self.stop_cpu != NO_CPU
+    }
+
+    /// What the trace records, from the bits of its tag above [`TAG_MASK`].
+    pub fn kind(&self) -> RecordKind {
+        match (self.tag >> KIND_SHIFT) & KIND_BITS {
+            0 => RecordKind::Span,
+            1 => RecordKind::FlowStart,
+            2 => RecordKind::FlowStep,
+            3 => RecordKind::Instant,
+            4 => RecordKind::Counter,
+            kind => RecordKind::Unknown(kind as u8),
+        }
+    }
+
+    /// The tag without its kind and flags, as given to `trace_span!`, and as names are registered for.
+    pub fn base_tag(&self) -> u64 {
+        self.tag & TAG_MASK
+    }
+
+    pub(crate) fn words(self) -> [u64; TRACE_WORDS] {
+        bytemuck::cast(self)
+    }
+}
+
+/// How much of a thread's ring of traces has been used.
+///
+/// The Chrome JSON and Perfetto exporters mark a thread that lost traces (see [`lost`](Self::lost))
+/// with a "traces lost" instant event at its first trace, whose arguments are its recorded, overwritten and dropped counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceStats {
+    /// Same thread id used by [`write_all_threads_csv`] and [`write_all_threads_binary`].
+    pub thread_id: u64,
+    /// Number of traces ever recorded by the thread, or since its buffer was last flushed with [`FullPolicy::Flush`].
+    pub recorded: u64,
+    /// Number of those traces that were overwritten when the ring wrapped around.
+    pub overwritten: u64,
+    /// Number of traces that weren't recorded because the buffer was full with [`FullPolicy::Stop`].
+    pub dropped: u64,
+}
+
+impl TraceStats {
+    pub(crate) fn new(thread_id: u64, (recorded, held, dropped): (u64, u64, u64)) -> Self {
+        TraceStats {
+            thread_id,
+            recorded,
+            overwritten: recorded.saturating_sub(held),
+            dropped,
+        }
+    }
+
+    /// True if the ring has wrapped around, so the oldest traces are gone.
+    pub fn wrapped(&self) -> bool {
+        self.overwritten > 0
+    }
+
+    /// Number of traces lost, either overwritten or dropped.
+    pub fn lost(&self) -> u64 {
+        self.overwritten + self.dropped
+    }
+
+    /// Name and arguments of the exporters' "traces lost" event, or None if the thread lost nothing.
+    pub(crate) fn lost_event(&self) -> Option<(&'static str, [(&'static str, u64); 3])> {
+        (self.lost() > 0).then_some((
+            "traces lost",
+            [
+                ("recorded", self.recorded),
+                ("overwritten", self.overwritten),
+                ("dropped", self.dropped),
+            ],
+        ))
+    }
+}
+
+/// Returns a snapshot of the current thread's traces, oldest to newest.
+///
+/// Useful in tests of instrumented code, along with [`MockClock`] for predictable timestamps
+/// and [`clear_thread_traces`] to start each test with an empty buffer.
+pub fn traces_in_order() -> impl Iterator<Item = Trace> {
+    registry::with_current(|buffer| {
+        buffer.records().collect::<Vec<_>>()
+    })
+    .into_iter()
+}
+
+/// Discards the current thread's traces, as if it had never recorded any.
+/// With the `"nesting"` feature, span ids start over from 0 if no spans are open.
+pub fn clear_thread_traces() {
+    registry::with_current(|buffer| buffer.clear());
+    #[cfg(feature = "nesting")]
+    nesting::reset();
+}
+
+/// Reports whether the current thread's ring has wrapped, and how many traces were overwritten or dropped.
+pub fn trace_stats() -> TraceStats {
+    registry::with_current_entry(|thread_id, buffer| TraceStats::new(thread_id, buffer.counts()))
+}
+
+/// Reports [`trace_stats`] for every thread included by [`write_all_threads_csv`] and [`write_all_threads_binary`].
+pub fn all_threads_trace_stats() -> Vec<TraceStats> {
+    let mut stats = vec![];
+    registry::for_each_count(|thread_id, counts| stats.push(TraceStats::new(thread_id, counts)));
+    stats
 }
 
 /// Writes the current thread's array of traces in the format:
 ///
-/// tag,start_rdtsc,stop_rdtsc,stop_minus_start\n
+/// tag,start_rdtsc,stop_rdtsc,stop_minus_start,kind\n
+///
+/// where kind is `span` (or `lifetime` for a [`Traced`] future's whole-lifetime span), `instant`, `counter`,
+/// `flow_start`, `flow_step`, or a number for kinds this build doesn't know.
+/// Only spans have a stop_minus_start. Instants leave stop_rdtsc empty, counters have their value there,
+/// and flows have their id there, with an empty tag.
 ///
-/// Stops writing once it encounters a stop_rdtsc of zero,
-/// assuming that's an unused portion of the array
+/// With the `"cpu_id"` feature, each row ends with `,start_cpu,stop_cpu,migrated` instead,
+/// where migrated is 1 if the span started and stopped on different CPUs, and an unknown CPU is empty.
+/// With the `"nesting"` feature, each row then ends with `,id,parent,depth`, where parent is empty at the top level.
+/// With the `"thread_id"` feature, each row then ends with `,start_thread,stop_thread`, empty except for spans from [`begin`].
+/// With the `"payload"` feature, each row then ends with `,arg0,arg1`, empty where no value was given.
+///
+/// Traces are written oldest to newest, even after the ring has wrapped around.
+/// Only slots that have been filled are written; the number of those is tracked explicitly,
+/// so traces with a zero in any field (e.g. from `insert_trace!`) are written like any other.
+/// stop_minus_start wraps rather than panicking if stop is less than start.
 pub fn write_traces_csv(writer: &mut impl Write) -> Result<()> {
-    let mut res = Ok(());
-    TSC_TRACE_SPANS.with(|spans| {
-        let spans = spans.borrow();
-        for chunk in spans.chunks_exact(3) {
-            if let &[tag, start, stop] = chunk {
-                if stop == 0 {
-                    break;
-                }
-                if let e @ Err(_) = writeln!(writer, "{tag},{start},{stop},{}", stop - start) {
-                    res = e;
-                    break;
-                }
-            }
+    registry::with_current(|buffer| {
+        for trace in buffer.records() {
+            write_csv_row(writer, &trace, None)?;
+        }
+        Ok(())
+    })
+}
+
+/// Like [`write_traces_csv`], with the duration converted to nanoseconds using [`ticks_per_ns`]:
+///
+/// tag,start_rdtsc,stop_rdtsc,stop_minus_start,stop_minus_start_ns,kind\n
+pub fn write_traces_csv_ns(writer: &mut impl Write) -> Result<()> {
+    let ticks_per_ns = ticks_per_ns();
+    registry::with_current(|buffer| {
+        for trace in buffer.records() {
+            write_csv_row(writer, &trace, Some(ticks_per_ns))?;
         }
-    });
-    res
+        Ok(())
+    })
 }
 
 /// Writes the current thread's array of traces in a binary format.
@@ -85,23 +421,155 @@ pub fn write_traces_csv(writer: &mut impl Write) -> Result<()> {
 /// start_rdtsc: u64
 /// stop_rdtsc: u64
 ///
+/// followed, with the `"cpu_id"` feature, by start_cpu: u32 and stop_cpu: u32,
+/// with the `"nesting"` feature, by id: u64, parent: u64 and depth: u64,
+/// with the `"thread_id"` feature, by start_thread: u32 and stop_thread: u32,
+/// and with the `"payload"` feature, by arg0: u64 and arg1: u64.
 /// There are no delimiters between each field or between traces.
-/// Assumes little-endian since this library only works for x86.
-/// Unlike print_csv, the difference between stop and start is not calculated.
-/// Writes the entire array, even zeroed / unused portions.
+/// Every field is in this machine's byte order, so little-endian on x86 and (usually) aarch64 and RISC-V,
+/// but nothing records which, unlike [`write_trace_file`].
+/// Unlike print_csv, the difference between stop and start is not calculated,
+/// and the tag includes the bits above [`TAG_MASK`] that say what kind of record it is.
+/// Writes every slot the thread has filled so far, oldest to newest.
+/// Unused slots are never written, even with the `"const_array"` feature.
 ///
 /// This is suitable for import to Clickhouse via format RowBinary
 /// <https://clickhouse.com/docs/en/interfaces/formats#rowbinary>
 pub fn write_traces_binary(writer: &mut impl Write) -> Result<()> {
-    let mut res = Ok(());
-    TSC_TRACE_SPANS.with(|spans| {
-        let spans = spans.borrow();
-        let bytes: &[u8] = bytemuck::cast_slice(&*spans);
-        if let e @ Err(_) = writer.write_all(&bytes) {
-            res = e;
+    registry::with_current(|buffer| write_words(writer, buffer.records().flat_map(Trace::words)))
+}
+
+/// Writes the traces of every thread that has recorded any, in the format:
+///
+/// thread_id,tag,start_rdtsc,stop_rdtsc,stop_minus_start,kind\n
+///
+/// with the columns after thread_id as in [`write_traces_csv`].
+/// With the `"cpu_id"`, `"nesting"`, `"thread_id"` or `"payload"` features, each row ends with the same columns as [`write_traces_csv`].
+/// Each thread's traces are written oldest to newest; see [`all_threads_trace_stats`] for how many were overwritten.
+/// Thread ids are assigned by this library in the order threads record their first trace
+/// (or, with the `"thread_id"` feature, [`begin`] a span), starting at 1.
+/// Threads that have already exited are included, until [`clear_exited_threads`] is called.
+/// Each thread's traces are copied before being written, so threads aren't held up by a slow writer,
+/// and this needs memory for a copy of the largest thread's traces.
+pub fn write_all_threads_csv(writer: &mut impl Write) -> Result<()> {
+    registry::for_each_thread(|thread| {
+        let thread_id = thread.thread_id;
+        for trace in thread.traces {
+            write!(writer, "{thread_id},")?;
+            write_csv_row(writer, &trace, None)?;
+        }
+        Ok(())
+    })
+}
+
+/// Like [`write_all_threads_csv`], with the duration converted to nanoseconds using [`ticks_per_ns`]:
+///
+/// thread_id,tag,start_rdtsc,stop_rdtsc,stop_minus_start,stop_minus_start_ns,kind\n
+pub fn write_all_threads_csv_ns(writer: &mut impl Write) -> Result<()> {
+    let ticks_per_ns = ticks_per_ns();
+    registry::for_each_thread(|thread| {
+        let thread_id = thread.thread_id;
+        for trace in thread.traces {
+            write!(writer, "{thread_id},")?;
+            write_csv_row(writer, &trace, Some(ticks_per_ns))?;
+        }
+        Ok(())
+    })
+}
+
+/// Writes the traces of every thread that has recorded any in a binary format.
+/// This is, in order:
+///
+/// thread_id: u64
+/// tag: u64
+/// start_rdtsc: u64
+/// stop_rdtsc: u64
+///
+/// followed by the same optional fields as [`write_traces_binary`].
+/// As with [`write_traces_binary`], there are no delimiters, so this is also suitable for Clickhouse RowBinary.
+/// Thread ids are the same as in [`write_all_threads_csv`].
+pub fn write_all_threads_binary(writer: &mut impl Write) -> Result<()> {
+    registry::for_each_thread(|thread| {
+        let thread_id = thread.thread_id;
+        let words = thread
+            .traces
+            .into_iter()
+            .flat_map(|trace| std::iter::once(thread_id).chain(trace.words()));
+        write_words(writer, words)
+    })
+}
+
+/// Frees the traces kept for threads that have exited,
+/// e.g. after they've been written by [`write_all_threads_csv`] or [`write_all_threads_binary`].
+pub fn clear_exited_threads() {
+    registry::clear_exited();
+}
+
+/// Writes a trace's CSV row, from tag on, with a stop_minus_start_ns column if `ticks_per_ns` is given.
+fn write_csv_row(writer: &mut impl Write, trace: &Trace, ticks_per_ns: Option<f64>) -> Result<()> {
+    let Trace { start, stop, .. } = *trace;
+    let tag = trace.base_tag();
+    let empty_ns = if ticks_per_ns.is_some() { "," } else { "" };
+    match trace.kind() {
+        RecordKind::Span => {
+            let ticks = stop.wrapping_sub(start);
+            write!(writer, "{tag},{start},{stop},{ticks}")?;
+            if let Some(ticks_per_ns) = ticks_per_ns {
+                write!(writer, ",{}", (ticks as f64 / ticks_per_ns).round() as u64)?;
+            }
+            let kind = if trace.tag & LIFETIME_FLAG != 0 { "lifetime" } else { "span" };
+            write!(writer, ",{kind}")?;
+        }
+        RecordKind::Instant => write!(writer, "{tag},{start},,{empty_ns},instant")?,
+        RecordKind::Counter => write!(writer, "{tag},{start},{stop},{empty_ns},counter")?,
+        RecordKind::FlowStart => write!(writer, ",{start},{stop},{empty_ns},flow_start")?,
+        RecordKind::FlowStep => write!(writer, ",{start},{stop},{empty_ns},flow_step")?,
+        RecordKind::Unknown(kind) => write!(writer, "{tag},{start},{stop},{empty_ns},{kind}")?,
+    }
+    end_csv_row(writer, trace)
+}
+
+/// Ends a CSV row, after the columns for any optional fields.
+#[cfg_attr(
+    not(any(feature = "cpu_id", feature = "nesting", feature = "thread_id", feature = "payload")),
+    allow(unused_variables)
+)]
+fn end_csv_row(writer: &mut impl Write, trace: &Trace) -> Result<()> {
+    // NO_CPU, NO_SPAN etc. are left empty, rather than written as u32::MAX or u64::MAX
+    #[cfg(feature = "cpu_id")]
+    {
+        let cpu = |cpu: u32| if cpu == NO_CPU { String::new() } else { cpu.to_string() };
+        write!(writer, ",{},{},{}", cpu(trace.start_cpu), cpu(trace.stop_cpu), trace.migrated() as u8)?;
+    }
+    #[cfg(feature = "nesting")]
+    {
+        let span = |id: u64| if id == NO_SPAN { String::new() } else { id.to_string() };
+        write!(writer, ",{},{},{}", span(trace.id), span(trace.parent), trace.depth)?;
+    }
+    #[cfg(feature = "thread_id")]
+    {
+        let thread = |id: u32| if id == NO_THREAD { String::new() } else { id.to_string() };
+        write!(writer, ",{},{}", thread(trace.start_thread), thread(trace.stop_thread))?;
+    }
+    #[cfg(feature = "payload")]
+    {
+        let arg = |arg: u64| if arg == NO_ARG { String::new() } else { arg.to_string() };
+        write!(writer, ",{},{}", arg(trace.args[0]), arg(trace.args[1]))?;
+    }
+    writeln!(writer)
+}
+
+/// Writes u64s in native byte order, a chunk at a time rather than one write call per word.
+pub(crate) fn write_words(writer: &mut impl Write, words: impl Iterator<Item = u64>) -> Result<()> {
+    let mut chunk: Vec<u64> = Vec::with_capacity(4096);
+    for word in words {
+        chunk.push(word);
+        if chunk.len() == chunk.capacity() {
+            writer.write_all(bytemuck::cast_slice(&chunk))?;
+            chunk.clear();
         }
-    });
-    res
+    }
+    writer.write_all(bytemuck::cast_slice(&chunk))
 }
 
 /// Reads the processor's timestamp counter. If the `"lfence"` feature is enabled, includes lfence instructions before and after.
@@ -152,9 +620,42 @@ pub fn rdtsc() -> u64 {
     r
 }
 
+/// There's no timestamp counter instruction on this architecture, so this reads the [`ActiveClock`] instead, like [`now`].
+#[inline(always)]
 #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
 pub fn rdtsc() -> u64 {
-    unimplemented!("x86 or x86_64 needed for rdtsc, aarch64 needed for workaround")
+    now()
+}
+
+/// Reads the processor's timestamp counter along with the id of the CPU it was read on.
+/// That comes from `IA32_TSC_AUX`, where Linux stores the CPU number in the low 12 bits and the NUMA node above them;
+/// only the CPU number is returned.
+/// If the `"lfence"` feature is enabled, includes an lfence instruction after, as rdtscp already waits for earlier instructions.
+#[inline(always)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn rdtscp() -> (u64, u32) {
+    #[cfg(all(feature = "lfence", target_arch = "x86"))]
+    use core::arch::x86::_mm_lfence;
+    #[cfg(target_arch = "x86")]
+    use core::arch::x86::__rdtscp;
+    #[cfg(all(feature = "lfence", target_arch = "x86_64"))]
+    use core::arch::x86_64::_mm_lfence;
+    #[cfg(target_arch = "x86_64")]
+    use core::arch::x86_64::__rdtscp;
+    let mut cpu = 0;
+    unsafe {
+        let r = __rdtscp(&mut cpu);
+        #[cfg(feature = "lfence")]
+        _mm_lfence();
+        (r, cpu & 0xfff)
+    }
+}
+
+/// There's no equivalent of rdtscp outside x86, so this reads [`rdtsc`] along with [`clock::current_cpu`].
+#[inline(always)]
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+pub fn rdtscp() -> (u64, u32) {
+    (rdtsc(), clock::current_cpu())
 }
 
 /// This struct must be public so that the trace_span! macro can make an instance of it in your code.
@@ -162,85 +663,207 @@ pub fn rdtsc() -> u64 {
 pub struct TraceSpan {
     tag: u64,
     start: u64,
+    #[cfg(feature = "cpu_id")]
+    start_cpu: u32,
+    #[cfg(feature = "nesting")]
+    position: nesting::Position,
+    #[cfg(feature = "payload")]
+    args: [u64; 2],
 }
 
 impl TraceSpan {
     /// Do not call this, use the trace_span! macro instead.
+    #[inline(always)]
     pub fn new(tag: u64) -> Self {
+        Self::with_args(tag, [NO_ARG; 2])
+    }
+
+    /// Do not call this, use the trace_span! macro instead.
+    /// Without the `"payload"` feature, `args` are ignored.
+    #[cfg_attr(not(feature = "payload"), allow(unused_variables))]
+    pub fn with_args(tag: u64, args: [u64; 2]) -> Self {
+        let tag = user_tag(tag);
+        #[cfg(feature = "nesting")]
+        let position = nesting::enter();
+        #[cfg(not(feature = "cpu_id"))]
+        let start = ActiveClock::now();
+        #[cfg(feature = "cpu_id")]
+        let (start, start_cpu) = ActiveClock::now_with_cpu();
         TraceSpan {
             tag,
-            start: rdtsc(),
+            start,
+            #[cfg(feature = "cpu_id")]
+            start_cpu,
+            #[cfg(feature = "nesting")]
+            position,
+            #[cfg(feature = "payload")]
+            args,
         }
     }
+
+    /// Sets one of the two values recorded with the span, e.g. once a request's size is known.
+    /// Bind the span to a name to call this with `trace_span!(let mut span = tag)`.
+    /// Without the `"payload"` feature, nothing is stored.
+    #[cfg_attr(not(feature = "payload"), allow(unused_variables))]
+    pub fn set_arg(&mut self, index: ArgIndex, value: u64) {
+        #[cfg(feature = "payload")]
+        {
+            self.args[index as usize] = value;
+        }
+    }
+
+    /// Clock reading when the span started.
+    pub(crate) fn start(&self) -> u64 {
+        self.start
+    }
 }
 
 impl Drop for TraceSpan {
     fn drop(&mut self) {
-        let stop = rdtsc();
-        _insert_trace(self.tag, self.start, stop);
+        #[cfg(not(feature = "cpu_id"))]
+        let stop = ActiveClock::now();
+        #[cfg(feature = "cpu_id")]
+        let (stop, stop_cpu) = ActiveClock::now_with_cpu();
+        #[cfg(feature = "nesting")]
+        nesting::exit(self.position);
+        let trace = Trace {
+            tag: self.tag,
+            start: self.start,
+            stop,
+            #[cfg(feature = "cpu_id")]
+            start_cpu: self.start_cpu,
+            #[cfg(feature = "cpu_id")]
+            stop_cpu,
+            #[cfg(feature = "nesting")]
+            id: self.position.id,
+            #[cfg(feature = "nesting")]
+            parent: self.position.parent,
+            #[cfg(feature = "nesting")]
+            depth: self.position.depth,
+            #[cfg(feature = "thread_id")]
+            start_thread: NO_THREAD,
+            #[cfg(feature = "thread_id")]
+            stop_thread: NO_THREAD,
+            #[cfg(feature = "payload")]
+            args: self.args,
+        };
+        registry::with_current(|buffer| buffer.insert(trace))
     }
 }
 
+/// What `trace_span!(let mut span = tag)` binds with the `"off"` feature, so code setting the span's values still compiles.
+pub struct DisabledSpan;
+
+impl DisabledSpan {
+    /// Does nothing, like the rest of tracing with the `"off"` feature.
+    pub fn set_arg(&mut self, _index: ArgIndex, _value: u64) {}
+}
+
+/// Must be public for use by the trace_instant! macro.
+/// Use that macro instead, don't use this directly.
+#[inline(always)]
+pub fn _trace_instant(tag: u64) {
+    let now = ActiveClock::now();
+    insert_record(user_tag(tag) | RecordKind::Instant.tag_bits(), now, now);
+}
+
+/// Must be public for use by the trace_counter! macro.
+/// Use that macro instead, don't use this directly.
+#[inline(always)]
+pub fn _trace_counter(tag: u64, value: u64) {
+    insert_record(user_tag(tag) | RecordKind::Counter.tag_bits(), ActiveClock::now(), value);
+}
+
 /// Must be public for use by the insert_trace! macro.
 /// Use that macro instead, don't use this directly.
 #[inline(always)]
 pub fn _insert_trace(tag: u64, start: u64, stop: u64) {
-    TSC_TRACE_INDEX.with(|index| {
-        let mut i = index.get();
-        if i >= CAPACITY {
-            i = 0;
-        }
-
-        #[cfg(feature = "const_array")]
-        TSC_TRACE_SPANS.with(|spans| {
-            let mut spans = spans.borrow_mut();
-            spans[i] = tag;
-            spans[i + 1] = start;
-            spans[i + 2] = stop;
-            i += 3;
-        });
-
-        #[cfg(not(feature = "const_array"))]
-        TSC_TRACE_SPANS.with(|spans| {
-            let mut spans = spans.borrow_mut();
-            if spans.len() >= CAPACITY {
-                spans[i] = tag;
-                spans[i + 1] = start;
-                spans[i + 2] = stop;
-                i += 3;
-            } else {
-                spans.push(tag);
-                spans.push(start);
-                spans.push(stop);
-                i += 3;
-            }
-        });
+    insert_record(user_tag(tag), start, stop)
+}
 
-        index.set(i);
-    })
+/// Records a trace whose tag may have bits above [`TAG_MASK`] set, e.g. its kind,
+/// as a child of the innermost open span with the `"nesting"` feature.
+#[inline(always)]
+pub(crate) fn insert_record(tag: u64, start: u64, stop: u64) {
+    #[cfg_attr(not(feature = "nesting"), allow(unused_mut))]
+    let mut trace = Trace::new(tag, start, stop);
+    #[cfg(feature = "nesting")]
+    {
+        let position = nesting::leaf();
+        trace.id = position.id;
+        trace.parent = position.parent;
+        trace.depth = position.depth;
+    }
+    registry::with_current(|buffer| buffer.insert(trace))
 }
 
 #[macro_export]
 #[cfg(not(feature = "off"))]
 /// `trace_span!(tag)` Starts a trace span with the given u64 tag that ends at the end of this scope.
 /// Creates a local variable named _tsc_trace_span, so don't use that name yourself.
+/// The tag must fit in [`TAG_MASK`] (56 bits), as for every macro here:
+/// a larger one (e.g. a negative enum discriminant) panics in debug builds, and has its top bits cut off in release builds.
+///
+/// `trace_span!()` tags the span by where it is instead: [`name_tag`] of `"module::path file.rs:line:column"`,
+/// which is registered as the tag's name the first time the span starts.
+/// Module paths start with the crate name, so these don't clash between crates.
+///
+/// `trace_span!(tag, arg0)` and `trace_span!(tag, arg0, arg1)` also record one or two values with the span,
+/// e.g. a request's size, taking anything that `as u64` works on. They're evaluated when the span starts.
+/// The values are only stored with the `"payload"` feature; without it they're evaluated and ignored.
+///
+/// `trace_span!(let mut span = tag)`, optionally with values as above, binds the span to `span` instead,
+/// so its values can be set later with [`TraceSpan::set_arg`], e.g. `span.set_arg(ArgIndex::Arg0, len)`.
+/// With the `"off"` feature, `span` is a [`DisabledSpan`] whose `set_arg` does nothing.
 macro_rules! trace_span {
+    (let mut $span:ident = $e:expr) => {
+        let mut $span = $crate::TraceSpan::new(($e) as u64);
+    };
+    (let mut $span:ident = $e:expr, $a:expr) => {
+        let mut $span = $crate::TraceSpan::with_args(($e) as u64, [($a) as u64, $crate::NO_ARG]);
+    };
+    (let mut $span:ident = $e:expr, $a:expr, $b:expr) => {
+        let mut $span = $crate::TraceSpan::with_args(($e) as u64, [($a) as u64, ($b) as u64]);
+    };
+    () => {
+        let _tsc_trace_span = {
+            static REGISTERED: ::std::sync::Once = ::std::sync::Once::new();
+            const NAME: &str = concat!(module_path!(), " ", file!(), ":", line!(), ":", column!());
+            const TAG: u64 = $crate::name_tag(NAME);
+            $crate::_register_tag_once(&REGISTERED, TAG, NAME);
+            $crate::TraceSpan::new(TAG)
+        };
+    };
     ($e:expr) => {
         let _tsc_trace_span = TraceSpan::new(($e) as u64);
     };
+    ($e:expr, $a:expr) => {
+        let _tsc_trace_span = $crate::TraceSpan::with_args(($e) as u64, [($a) as u64, $crate::NO_ARG]);
+    };
+    ($e:expr, $a:expr, $b:expr) => {
+        let _tsc_trace_span = $crate::TraceSpan::with_args(($e) as u64, [($a) as u64, ($b) as u64]);
+    };
 }
 
 #[macro_export]
 #[cfg(feature = "off")]
 macro_rules! trace_span {
+    (let mut $span:ident = $e:expr $(, $a:expr)*) => {
+        let mut $span = $crate::DisabledSpan;
+    };
+    () => {};
     ($e:expr) => {};
+    ($e:expr, $a:expr) => {};
+    ($e:expr, $a:expr, $b:expr) => {};
 }
 
 #[macro_export]
 #[cfg(not(feature = "off"))]
 /// `insert_trace!(tag, start, stop)`
-/// Takes any 3 arbitrary expressions that `as u64` works on,
-/// immediately inserts them into the thread local array as if they were a single trace.
+/// Takes 3 expressions that `as u64` works on,
+/// immediately inserts them into the thread local array as if they were a single span.
+/// Start and stop can be any values, but the tag must fit in [`TAG_MASK`], as for `trace_span!`,
+/// since the bits above it say what kind of record a trace is.
 macro_rules! insert_trace {
     ($a:expr, $b:expr, $c:expr) => {
         _insert_trace(($a) as u64, ($b) as u64, ($c) as u64);
@@ -252,3 +875,35 @@ macro_rules! insert_trace {
 macro_rules! insert_trace {
     ($a:expr, $b:expr, $c:expr) => {};
 }
+
+#[macro_export]
+#[cfg(not(feature = "off"))]
+/// `trace_instant!(tag)` Records that something happened now, as a single timestamp rather than a span.
+/// The tag must fit in [`TAG_MASK`], as for `trace_span!`.
+macro_rules! trace_instant {
+    ($e:expr) => {
+        $crate::_trace_instant(($e) as u64);
+    };
+}
+
+#[macro_export]
+#[cfg(feature = "off")]
+macro_rules! trace_instant {
+    ($e:expr) => {};
+}
+
+#[macro_export]
+#[cfg(not(feature = "off"))]
+/// `trace_counter!(tag, value)` Records a sample of a counter, e.g. a queue's length, with the current time.
+/// Takes any value that `as u64` works on. The tag must fit in [`TAG_MASK`], as for `trace_span!`.
+macro_rules! trace_counter {
+    ($tag:expr, $value:expr) => {
+        $crate::_trace_counter(($tag) as u64, ($value) as u64);
+    };
+}
+
+#[macro_export]
+#[cfg(feature = "off")]
+macro_rules! trace_counter {
+    ($tag:expr, $value:expr) => {};
+}
@@ -2,30 +2,36 @@
 use tsc_trace::*;
 
 // anything that `as u64` works on can be used as a tag for traces
+// deriving TraceTags lets all of an enum's tags be named at once
 #[allow(dead_code)]
+#[derive(TraceTags)]
 enum Traces {
     Main = 0,
     SomeFunction = 1,
     SomeEvent = 2,
+    QueueLen = 3,
 }
 
 fn main() -> std::io::Result<()> {
+    // name tags, so tools reading trace files can show names rather than numbers
+    Traces::register_tags();
+
     {
         trace_span!(Traces::Main); // reads rdtsc to get cycle count, stores it in a stack variable
-        for _ in 1..10 {
-            some_function();
+        for i in 1..10 {
+            some_function(i);
         }
     } // drop impl of that variable reads rdtsc again, stores the tag and both cycle counts in a thread local array
 
-    // immediately store info (normally tag, start, stop, but can be any 3 u64 you want) to the array of traces
-    insert_trace!(
-        Traces::SomeEvent,
-        rdtsc(),
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos()
-    );
+    // record that something happened at a single point in time
+    trace_instant!(Traces::SomeEvent);
+
+    // record a sample of some value, e.g. a queue length, which the viewer plots as a line
+    let mut queue = vec![];
+    for i in 0..3 {
+        queue.push(i);
+        trace_counter!(Traces::QueueLen, queue.len());
+    }
 
     // write the array of traces to stdout in comma-separated format
     let stdout = std::io::stdout();
@@ -33,10 +39,14 @@ fn main() -> std::io::Result<()> {
     write_traces_csv(&mut lock)?;
 
     // can do runtime checks against configured capacity
-    if TSC_TRACE_CAPACITY > 0 {
+    if capacity() > 0 {
         // write the array of traces to binary file
         let mut bin = std::fs::File::create("/tmp/traces")?;
         write_traces_binary(&mut bin)?;
+
+        // write every thread's traces to a self-describing file, along with tag names and ticks per nanosecond
+        let mut file = std::io::BufWriter::new(std::fs::File::create("/tmp/traces_file")?);
+        write_trace_file(&mut file)?;
     } else {
         println!("tracing is off, not writing binary file");
     }
@@ -44,7 +54,8 @@ fn main() -> std::io::Result<()> {
     Ok(())
 }
 
-fn some_function() {
-    trace_span!(Traces::SomeFunction);
-    println!("doing some work in some_function");
+fn some_function(size: usize) {
+    // also records a value with the span, e.g. how much work it was given, with the "payload" feature
+    trace_span!(Traces::SomeFunction, size);
+    println!("doing {size} units of work in some_function");
 }
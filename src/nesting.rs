@@ -0,0 +1,131 @@
+//! The current thread's stack of open spans, for recording each span's parent and depth with the `"nesting"` feature.
+
+use std::cell::{Cell, RefCell};
+
+use crate::NO_SPAN;
+
+thread_local! {
+    /// id the next span on this thread will get
+    static NEXT_ID: Cell<u64> = const { Cell::new(0) };
+    /// id of the innermost open span
+    static CURRENT: Cell<u64> = const { Cell::new(NO_SPAN) };
+    /// number of open spans
+    static DEPTH: Cell<u64> = const { Cell::new(0) };
+    /// spans closed while a span inside them was still open, which stay on the stack until that one closes
+    static CLOSED: RefCell<Vec<Position>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Where a span sits in its thread's stack of spans.
+#[derive(Clone, Copy)]
+pub(crate) struct Position {
+    pub(crate) id: u64,
+    pub(crate) parent: u64,
+    pub(crate) depth: u64,
+}
+
+fn next_position() -> Position {
+    let id = NEXT_ID.with(|next| next.replace(next.get() + 1));
+    Position {
+        id,
+        parent: CURRENT.with(Cell::get),
+        depth: DEPTH.with(Cell::get),
+    }
+}
+
+/// Opens a span inside the innermost open one.
+#[inline(always)]
+pub(crate) fn enter() -> Position {
+    let position = next_position();
+    CURRENT.with(|current| current.set(position.id));
+    DEPTH.with(|depth| depth.set(position.depth + 1));
+    position
+}
+
+/// Closes the span that `enter` returned `position` for, making its parent the innermost open span again.
+/// Spans can close out of order, e.g. guards dropped out of order; one that isn't innermost is only
+/// taken off the stack once the spans inside it have closed, so those keep their depth and later spans nest correctly.
+#[inline(always)]
+pub(crate) fn exit(position: Position) {
+    if CURRENT.with(Cell::get) != position.id {
+        CLOSED.with(|closed| closed.borrow_mut().push(position));
+        return;
+    }
+    let mut position = position;
+    loop {
+        CURRENT.with(|current| current.set(position.parent));
+        DEPTH.with(|depth| depth.set(position.depth));
+        let parent = CLOSED.with(|closed| {
+            let mut closed = closed.borrow_mut();
+            let i = closed.iter().position(|closed| closed.id == position.parent)?;
+            Some(closed.swap_remove(i))
+        });
+        match parent {
+            Some(parent) => position = parent,
+            None => break,
+        }
+    }
+}
+
+/// A trace inserted directly, which is a child of the innermost open span but doesn't open one itself.
+#[inline(always)]
+pub(crate) fn leaf() -> Position {
+    next_position()
+}
+
+/// Starts ids over from 0, unless spans are still open, whose ids could then be reused.
+pub(crate) fn reset() {
+    if DEPTH.with(Cell::get) == 0 {
+        NEXT_ID.with(|next| next.set(0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn innermost() -> (u64, u64) {
+        (CURRENT.with(Cell::get), DEPTH.with(Cell::get))
+    }
+
+    #[test]
+    fn nests_spans_closed_in_order() {
+        let a = enter();
+        let b = enter();
+        assert_eq!((b.parent, b.depth), (a.id, 1));
+        exit(b);
+        assert_eq!(innermost(), (a.id, 1));
+        exit(a);
+        assert_eq!(innermost(), (a.parent, 0));
+    }
+
+    #[test]
+    fn outer_span_closed_first_stays_open_until_inner_one_closes() {
+        let outside = innermost();
+        let a = enter();
+        let b = enter();
+        exit(a);
+        assert_eq!(innermost(), (b.id, 2));
+        let c = enter();
+        assert_eq!((c.parent, c.depth), (b.id, 2));
+        exit(c);
+        exit(b);
+        assert_eq!(innermost(), outside);
+        let d = enter();
+        assert_eq!((d.parent, d.depth), outside);
+        exit(d);
+    }
+
+    #[test]
+    fn spans_closed_in_any_order_all_come_off_the_stack() {
+        let outside = innermost();
+        let a = enter();
+        let b = enter();
+        let c = enter();
+        exit(b);
+        exit(a);
+        assert_eq!(innermost(), (c.id, 3));
+        exit(c);
+        assert_eq!(innermost(), outside);
+        assert!(CLOSED.with(|closed| closed.borrow().is_empty()));
+    }
+}
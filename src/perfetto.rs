@@ -0,0 +1,740 @@
+//! Export to Perfetto's native protobuf trace format, which <https://ui.perfetto.dev> loads far faster than JSON.
+//!
+//! Writes a `Trace` message: a sequence of `TracePacket`s, as described by
+//! <https://perfetto.dev/docs/reference/trace-packet-proto>.
+//! The handful of messages needed are encoded by hand, rather than depending on a protobuf library.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Result, Write};
+
+use crate::{registry, tags, ticks_per_ns, ActiveClock, ClockSource, FlowSpans, RecordKind, Trace, TraceReader, TraceStats};
+
+/// Writes the current thread's traces in Perfetto's protobuf format.
+/// See [`write_all_threads_perfetto`] for the details.
+pub fn write_traces_perfetto(writer: &mut impl Write) -> Result<()> {
+    let mut perfetto = Perfetto::in_process(writer)?;
+    let (stats, traces) = registry::with_current_entry(|thread_id, buffer| {
+        (TraceStats::new(thread_id, buffer.counts()), buffer.records().collect::<Vec<_>>())
+    });
+    perfetto.thread(stats.thread_id, std::thread::current().name(), Some(stats), &traces)
+}
+
+/// Writes the traces of every thread that has recorded any in Perfetto's protobuf format,
+/// which <https://ui.perfetto.dev> can open, and handles much larger traces than JSON.
+///
+/// Each thread becomes a track, and each trace a slice on it, named by the name given to [`register_tag`](crate::register_tag)
+/// (or the tag's number). Names are interned, so each is only written once.
+/// Timestamps are converted to nanoseconds using [`ticks_per_ns`].
+/// Traces that overlap without nesting go on extra tracks under the thread's, since slices on one track must nest.
+/// Traces whose stop is before their start are left out.
+/// Optional fields, like the CPU ids with the `"cpu_id"` feature, the span id, parent and depth with `"nesting"`,
+/// or the values given to `trace_span!(tag, arg0, arg1)` with `"payload"`, become the slice's arguments.
+/// A span from [`begin`](crate::begin) is on the track of the thread that ended it.
+/// Each flow step from [`flow_start`](crate::flow_start) and [`flow_step`](crate::flow_step) becomes a flow id
+/// on the slice it was recorded in (see [`FlowSpans`]), which the Perfetto UI draws as arrows between those slices.
+/// Instants from `trace_instant!` are instant events on their thread's track,
+/// and samples from `trace_counter!` go on a counter track for each tag, named like slices.
+/// A thread that lost traces has a "traces lost" instant event, see [`TraceStats`].
+/// The clock traces were timed with is in the process track's name, e.g. "Process 1234 (rdtsc clock)".
+///
+/// A thread's traces are copied and put in order of start before being written,
+/// so this needs memory for one more copy of the largest thread's traces, and an index for each.
+pub fn write_all_threads_perfetto(writer: &mut impl Write) -> Result<()> {
+    let mut perfetto = Perfetto::in_process(writer)?;
+    registry::for_each_thread(|thread| {
+        let stats = TraceStats::new(thread.thread_id, thread.counts);
+        perfetto.thread(thread.thread_id, thread.thread_name.as_deref(), Some(stats), &thread.traces)
+    })
+}
+
+/// Converts traces read from a file, e.g. by [`TraceReader::open`], to Perfetto's protobuf format.
+///
+/// As with [`write_chrome_json_from`](crate::write_chrome_json_from), bare traces from
+/// [`write_traces_binary`](crate::write_traces_binary) use the ticks per nanosecond of the machine doing the conversion.
+/// The clock is taken from the file's `"clock"` metadata, and left out of the process track's name if there is none.
+pub fn write_perfetto_from<R: Read>(
+    mut reader: TraceReader<R>,
+    writer: &mut impl Write,
+) -> Result<()> {
+    let pid = reader.pid();
+    let ticks_per_ns = reader
+        .header()
+        .map_or_else(ticks_per_ns, |header| header.ticks_per_ns);
+    let clock = reader.metadata_value("clock").map(str::to_owned);
+    let mut perfetto = Perfetto::new(writer, pid, ticks_per_ns, reader.tag_names().clone(), clock.as_deref())?;
+    let mut thread: Option<(u64, Option<String>, Option<TraceStats>)> = None;
+    let mut traces = vec![];
+    while let Some(trace) = reader.next() {
+        let trace = trace?;
+        let thread_id = reader.thread_id().unwrap_or(1);
+        if let Some((previous_id, name, stats)) = &thread {
+            if *previous_id != thread_id {
+                perfetto.thread(*previous_id, name.as_deref(), *stats, &traces)?;
+                traces.clear();
+            }
+        }
+        thread = Some((thread_id, reader.thread_name().map(str::to_owned), reader.thread_stats()));
+        traces.push(trace);
+    }
+    if let Some((thread_id, name, stats)) = thread {
+        perfetto.thread(thread_id, name.as_deref(), stats, &traces)?;
+    }
+    Ok(())
+}
+
+// Field numbers, from perfetto/protos/perfetto/trace
+const TRACE_PACKET: u32 = 1;
+const PACKET_TIMESTAMP: u32 = 8;
+const PACKET_SEQUENCE_ID: u32 = 10;
+const PACKET_TRACK_EVENT: u32 = 11;
+const PACKET_INTERNED_DATA: u32 = 12;
+const PACKET_SEQUENCE_FLAGS: u32 = 13;
+const PACKET_TRACK_DESCRIPTOR: u32 = 60;
+const TRACK_UUID: u32 = 1;
+const TRACK_NAME: u32 = 2;
+const TRACK_PROCESS: u32 = 3;
+const TRACK_THREAD: u32 = 4;
+const TRACK_PARENT_UUID: u32 = 5;
+const TRACK_COUNTER: u32 = 8;
+const PROCESS_PID: u32 = 1;
+const THREAD_PID: u32 = 1;
+const THREAD_TID: u32 = 2;
+const THREAD_NAME: u32 = 5;
+const EVENT_DEBUG_ANNOTATIONS: u32 = 4;
+const EVENT_TYPE: u32 = 9;
+const EVENT_NAME: u32 = 23;
+const EVENT_NAME_IID: u32 = 10;
+const EVENT_TRACK_UUID: u32 = 11;
+const EVENT_FLOW_IDS: u32 = 47;
+const EVENT_COUNTER_VALUE: u32 = 30;
+const INTERNED_EVENT_NAMES: u32 = 2;
+const EVENT_NAME_IID_FIELD: u32 = 1;
+const EVENT_NAME_NAME: u32 = 2;
+#[cfg(feature = "cpu_id")]
+const ANNOTATION_BOOL: u32 = 2;
+const ANNOTATION_UINT: u32 = 3;
+const ANNOTATION_NAME: u32 = 10;
+
+const SLICE_BEGIN: u64 = 1;
+const SLICE_END: u64 = 2;
+const INSTANT: u64 = 3;
+const COUNTER: u64 = 4;
+const SEQ_INCREMENTAL_STATE_CLEARED: u64 = 1;
+const SEQ_NEEDS_INCREMENTAL_STATE: u64 = 2;
+const SEQUENCE_ID: u64 = 1;
+const PROCESS_UUID: u64 = 1;
+/// Or'd with a tag for the uuid of its counter track, above those of threads and lanes.
+const COUNTER_UUID: u64 = 1 << 62;
+
+/// Encodes a protobuf message into a byte buffer.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn raw_varint(&mut self, mut n: u64) {
+        while n >= 0x80 {
+            self.0.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        self.0.push(n as u8);
+    }
+
+    fn varint(&mut self, field: u32, n: u64) {
+        self.raw_varint((field as u64) << 3);
+        self.raw_varint(n);
+    }
+
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        self.raw_varint((field as u64) << 3 | 2);
+        self.raw_varint(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn fixed64(&mut self, field: u32, n: u64) {
+        self.raw_varint((field as u64) << 3 | 1);
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn message(&mut self, field: u32, build: impl FnOnce(&mut Message)) {
+        let mut nested = Message::default();
+        build(&mut nested);
+        self.bytes(field, &nested.0);
+    }
+}
+
+struct Perfetto<'a, W: Write> {
+    writer: &'a mut W,
+    pid: u64,
+    ticks_per_ns: f64,
+    tag_names: BTreeMap<u64, String>,
+    /// interned name id of each tag seen so far
+    name_ids: HashMap<u64, u64>,
+    /// tags whose counter track has been described
+    counter_tracks: HashSet<u64>,
+    first_packet: bool,
+}
+
+impl<'a, W: Write> Perfetto<'a, W> {
+    fn new(
+        writer: &'a mut W,
+        pid: u64,
+        ticks_per_ns: f64,
+        tag_names: BTreeMap<u64, String>,
+        clock: Option<&str>,
+    ) -> Result<Self> {
+        let mut perfetto = Perfetto {
+            writer,
+            pid,
+            ticks_per_ns,
+            tag_names,
+            name_ids: HashMap::new(),
+            counter_tracks: HashSet::new(),
+            first_packet: true,
+        };
+        perfetto.packet(|packet| {
+            packet.message(PACKET_TRACK_DESCRIPTOR, |track| {
+                track.varint(TRACK_UUID, PROCESS_UUID);
+                if let Some(clock) = clock {
+                    track.bytes(TRACK_NAME, format!("Process {pid} ({clock} clock)").as_bytes());
+                }
+                track.message(TRACK_PROCESS, |process| process.varint(PROCESS_PID, pid));
+            });
+        })?;
+        Ok(perfetto)
+    }
+
+    /// For traces recorded by this process, calibrating first if that hasn't been done yet.
+    fn in_process(writer: &'a mut W) -> Result<Self> {
+        let ticks_per_ns = ticks_per_ns();
+        Perfetto::new(
+            writer,
+            std::process::id() as u64,
+            ticks_per_ns,
+            tags::tag_names(),
+            Some(ActiveClock::name()),
+        )
+    }
+
+    /// Writes one packet on the single sequence this exporter uses.
+    fn packet(&mut self, build: impl FnOnce(&mut Message)) -> Result<()> {
+        let mut packet = Message::default();
+        packet.varint(PACKET_SEQUENCE_ID, SEQUENCE_ID);
+        let flags = if self.first_packet {
+            SEQ_INCREMENTAL_STATE_CLEARED | SEQ_NEEDS_INCREMENTAL_STATE
+        } else {
+            SEQ_NEEDS_INCREMENTAL_STATE
+        };
+        self.first_packet = false;
+        packet.varint(PACKET_SEQUENCE_FLAGS, flags);
+        build(&mut packet);
+        let mut trace = Message::default();
+        trace.bytes(TRACE_PACKET, &packet.0);
+        self.writer.write_all(&trace.0)
+    }
+
+    fn track(&mut self, uuid: u64, build: impl FnOnce(&mut Message)) -> Result<()> {
+        self.packet(|packet| {
+            packet.message(PACKET_TRACK_DESCRIPTOR, |track| {
+                track.varint(TRACK_UUID, uuid);
+                build(track);
+            });
+        })
+    }
+
+    /// Nanoseconds since the counter started, which is usually boot, matching Perfetto's default clock.
+    fn nanos(&self, ticks: u64) -> u64 {
+        (ticks as f64 / self.ticks_per_ns) as u64
+    }
+
+    /// Writes a slice's begin, for which `trace` and the ids of flows through it are given, or its end.
+    fn event(&mut self, track: u64, ticks: u64, kind: u64, trace: Option<&Trace>, flows: &[u64]) -> Result<()> {
+        let timestamp = self.nanos(ticks);
+        let next_id = self.name_ids.len() as u64 + 1;
+        let (name_id, new_name) = match trace.map(Trace::base_tag) {
+            Some(tag) => {
+                let name_id = *self.name_ids.entry(tag).or_insert(next_id);
+                let new_name = (name_id == next_id).then(|| match self.tag_names.get(&tag) {
+                    Some(name) => name.clone(),
+                    None => tag.to_string(),
+                });
+                (Some(name_id), new_name)
+            }
+            None => (None, None),
+        };
+        self.packet(|packet| {
+            packet.varint(PACKET_TIMESTAMP, timestamp);
+            if let (Some(name_id), Some(name)) = (name_id, &new_name) {
+                packet.message(PACKET_INTERNED_DATA, |interned| {
+                    interned.message(INTERNED_EVENT_NAMES, |event_name| {
+                        event_name.varint(EVENT_NAME_IID_FIELD, name_id);
+                        event_name.bytes(EVENT_NAME_NAME, name.as_bytes());
+                    });
+                });
+            }
+            packet.message(PACKET_TRACK_EVENT, |event| {
+                event.varint(EVENT_TYPE, kind);
+                event.varint(EVENT_TRACK_UUID, track);
+                if let Some(name_id) = name_id {
+                    event.varint(EVENT_NAME_IID, name_id);
+                }
+                if let Some(trace) = trace {
+                    annotate(event, trace);
+                }
+                for &flow in flows {
+                    event.fixed64(EVENT_FLOW_IDS, flow);
+                }
+            });
+        })
+    }
+
+    /// Writes a counter sample, on a track for its tag under the process, described the first time it's used.
+    fn counter(&mut self, trace: &Trace) -> Result<()> {
+        let tag = trace.base_tag();
+        let uuid = COUNTER_UUID | tag;
+        if self.counter_tracks.insert(tag) {
+            let name = match self.tag_names.get(&tag) {
+                Some(name) => name.clone(),
+                None => tag.to_string(),
+            };
+            self.track(uuid, |track| {
+                track.varint(TRACK_PARENT_UUID, PROCESS_UUID);
+                track.bytes(TRACK_NAME, name.as_bytes());
+                track.message(TRACK_COUNTER, |_| {});
+            })?;
+        }
+        let timestamp = self.nanos(trace.start);
+        self.packet(|packet| {
+            packet.varint(PACKET_TIMESTAMP, timestamp);
+            packet.message(PACKET_TRACK_EVENT, |event| {
+                event.varint(EVENT_TYPE, COUNTER);
+                event.varint(EVENT_TRACK_UUID, uuid);
+                event.varint(EVENT_COUNTER_VALUE, trace.stop);
+            });
+        })
+    }
+
+    /// Writes an instant event at `ticks` saying how many traces the thread lost, if any.
+    fn lost(&mut self, track: u64, stats: &TraceStats, ticks: u64) -> Result<()> {
+        let Some((name, args)) = stats.lost_event() else {
+            return Ok(());
+        };
+        let timestamp = self.nanos(ticks);
+        self.packet(|packet| {
+            packet.varint(PACKET_TIMESTAMP, timestamp);
+            packet.message(PACKET_TRACK_EVENT, |event| {
+                event.varint(EVENT_TYPE, INSTANT);
+                event.varint(EVENT_TRACK_UUID, track);
+                event.bytes(EVENT_NAME, name.as_bytes());
+                for (arg, n) in args {
+                    uint_annotation(event, arg, n);
+                }
+            });
+        })
+    }
+
+    /// Writes a thread's track and slices, after a "traces lost" event if `stats` says it lost any.
+    /// `traces` are in the order they were recorded.
+    fn thread(
+        &mut self,
+        thread_id: u64,
+        name: Option<&str>,
+        stats: Option<TraceStats>,
+        traces: &[Trace],
+    ) -> Result<()> {
+        let pid = self.pid;
+        let thread_uuid = (thread_id + 1) << 16;
+        self.track(thread_uuid, |track| {
+            track.varint(TRACK_PARENT_UUID, PROCESS_UUID);
+            track.message(TRACK_THREAD, |thread| {
+                thread.varint(THREAD_PID, pid);
+                thread.varint(THREAD_TID, thread_id);
+                if let Some(name) = name {
+                    thread.bytes(THREAD_NAME, name.as_bytes());
+                }
+            });
+        })?;
+
+        // each flow goes on the slice it was recorded in, worked out from the order traces were recorded in
+        let mut flows: HashMap<usize, Vec<u64>> = HashMap::new();
+        let mut flow_spans = FlowSpans::new();
+        for (i, trace) in traces.iter().enumerate() {
+            for flow in flow_spans.push(trace) {
+                flows.entry(i).or_default().push(flow.stop);
+            }
+        }
+        for (flow, span) in flow_spans.finish() {
+            if let Some(span) = span {
+                flows.entry(span).or_default().push(flow.stop);
+            }
+        }
+
+        // parents before children, so each lane is a stack of the stops of the slices open on it
+        let mut order: Vec<usize> = (0..traces.len()).collect();
+        #[cfg(not(feature = "nesting"))]
+        order.sort_unstable_by_key(|&i| (traces[i].start, Reverse(traces[i].stop)));
+        // recorded depth settles which of two spans with the same start and stop is the parent
+        #[cfg(feature = "nesting")]
+        order.sort_unstable_by_key(|&i| (traces[i].start, Reverse(traces[i].stop), traces[i].depth));
+        let is_slice = |trace: &Trace| trace.kind() == RecordKind::Span && trace.stop >= trace.start;
+        if let (Some(stats), Some(&first)) = (stats, order.first()) {
+            self.lost(thread_uuid, &stats, traces[first].start)?;
+        }
+
+        let mut lanes: Vec<Vec<u64>> = vec![];
+        for i in order {
+            let trace = &traces[i];
+            let kind = trace.kind();
+            if !is_slice(trace) && kind != RecordKind::Instant && kind != RecordKind::Counter {
+                continue;
+            }
+            for (lane, open) in lanes.iter_mut().enumerate() {
+                while let Some(&stop) = open.last().filter(|&&stop| stop <= trace.start) {
+                    open.pop();
+                    self.event(thread_uuid + lane as u64, stop, SLICE_END, None, &[])?;
+                }
+            }
+            if kind == RecordKind::Instant {
+                self.event(thread_uuid, trace.start, INSTANT, Some(trace), &[])?;
+                continue;
+            }
+            if kind == RecordKind::Counter {
+                self.counter(trace)?;
+                continue;
+            }
+            let lane = match lanes
+                .iter()
+                .position(|open| open.last().is_none_or(|&stop| stop >= trace.stop))
+            {
+                Some(lane) => lane,
+                None => {
+                    lanes.push(vec![]);
+                    let lane = lanes.len() - 1;
+                    if lane > 0 {
+                        let name = format!("{} overlapping {lane}", name.unwrap_or("thread"));
+                        self.track(thread_uuid + lane as u64, |track| {
+                            track.varint(TRACK_PARENT_UUID, thread_uuid);
+                            track.bytes(TRACK_NAME, name.as_bytes());
+                        })?;
+                    }
+                    lane
+                }
+            };
+            lanes[lane].push(trace.stop);
+            let flows = flows.get(&i).map_or(&[][..], Vec::as_slice);
+            self.event(thread_uuid + lane as u64, trace.start, SLICE_BEGIN, Some(trace), flows)?;
+        }
+        for (lane, open) in lanes.iter_mut().enumerate() {
+            while let Some(stop) = open.pop() {
+                self.event(thread_uuid + lane as u64, stop, SLICE_END, None, &[])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adds a debug annotation with an unsigned value, which the Perfetto UI shows as one of the event's arguments.
+fn uint_annotation(event: &mut Message, name: &str, n: u64) {
+    event.message(EVENT_DEBUG_ANNOTATIONS, |annotation| {
+        annotation.bytes(ANNOTATION_NAME, name.as_bytes());
+        annotation.varint(ANNOTATION_UINT, n);
+    });
+}
+
+/// Adds a slice's optional fields as debug annotations, which the Perfetto UI shows as its arguments.
+#[cfg_attr(
+    not(any(feature = "cpu_id", feature = "nesting", feature = "thread_id", feature = "payload")),
+    allow(unused_variables)
+)]
+fn annotate(event: &mut Message, trace: &Trace) {
+    #[cfg(feature = "nesting")]
+    if trace.id != crate::NO_SPAN {
+        uint_annotation(event, "id", trace.id);
+        if trace.parent != crate::NO_SPAN {
+            uint_annotation(event, "parent", trace.parent);
+        }
+        uint_annotation(event, "depth", trace.depth);
+    }
+    #[cfg(feature = "thread_id")]
+    if trace.start_thread != crate::NO_THREAD {
+        uint_annotation(event, "start_thread", trace.start_thread as u64);
+        uint_annotation(event, "stop_thread", trace.stop_thread as u64);
+    }
+    #[cfg(feature = "payload")]
+    for (name, arg) in ["arg0", "arg1"].into_iter().zip(trace.args) {
+        if arg != crate::NO_ARG {
+            uint_annotation(event, name, arg);
+        }
+    }
+    #[cfg(feature = "cpu_id")]
+    if trace.start_cpu != crate::NO_CPU {
+        uint_annotation(event, "start_cpu", trace.start_cpu as u64);
+        uint_annotation(event, "stop_cpu", trace.stop_cpu as u64);
+        event.message(EVENT_DEBUG_ANNOTATIONS, |annotation| {
+            annotation.bytes(ANNOTATION_NAME, b"migrated");
+            annotation.varint(ANNOTATION_BOOL, trace.migrated() as u64);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug)]
+    enum Value<'a> {
+        Varint(u64),
+        Fixed64(u64),
+        Bytes(&'a [u8]),
+    }
+
+    fn varint(bytes: &mut &[u8]) -> u64 {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = bytes[0];
+            *bytes = &bytes[1..];
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        n
+    }
+
+    /// Splits a protobuf message into its fields, in order.
+    fn decode(mut bytes: &[u8]) -> Vec<(u32, Value<'_>)> {
+        let mut fields = vec![];
+        while !bytes.is_empty() {
+            let key = varint(&mut bytes);
+            let value = match key & 7 {
+                0 => Value::Varint(varint(&mut bytes)),
+                1 => {
+                    let (n, rest) = bytes.split_at(8);
+                    bytes = rest;
+                    Value::Fixed64(u64::from_le_bytes(n.try_into().unwrap()))
+                }
+                2 => {
+                    let len = varint(&mut bytes) as usize;
+                    let (nested, rest) = bytes.split_at(len);
+                    bytes = rest;
+                    Value::Bytes(nested)
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            fields.push(((key >> 3) as u32, value));
+        }
+        fields
+    }
+
+    fn uint(fields: &[(u32, Value)], field: u32) -> Option<u64> {
+        fields.iter().find_map(|&(f, value)| match value {
+            Value::Varint(n) if f == field => Some(n),
+            _ => None,
+        })
+    }
+
+    fn bytes<'a>(fields: &[(u32, Value<'a>)], field: u32) -> Option<&'a [u8]> {
+        fields.iter().find_map(|&(f, value)| match value {
+            Value::Bytes(bytes) if f == field => Some(bytes),
+            _ => None,
+        })
+    }
+
+    fn string(fields: &[(u32, Value)], field: u32) -> Option<String> {
+        bytes(fields, field).map(|bytes| String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    /// A track descriptor: parent uuid, name, thread id, and whether it's a counter track.
+    type Track = (Option<u64>, Option<String>, Option<u64>, bool);
+
+    #[test]
+    fn structure() {
+        let tag_names = BTreeMap::from([(1, "one"), (2, "two"), (5, "five"), (6, "six")].map(|(tag, name)| (tag, name.to_string())));
+        // in the order they'd be recorded, each span once it stops
+        let traces = [
+            Trace::new(RecordKind::FlowStart.tag_bits(), 15, 77),
+            Trace::new(2, 10, 20),
+            Trace::new(5 | RecordKind::Instant.tag_bits(), 30, 30),
+            Trace::new(6 | RecordKind::Counter.tag_bits(), 40, 99),
+            Trace::new(1, 0, 100),
+            // overlaps 1 without nesting in it, so goes on a lane of its own
+            Trace::new(3, 50, 150),
+        ];
+        let stats = TraceStats::new(0, (10, 6, 1));
+        let mut out = vec![];
+        let mut perfetto = Perfetto::new(&mut out, 42, 1.0, tag_names, Some("rdtsc")).unwrap();
+        perfetto.thread(0, Some("main"), Some(stats), &traces).unwrap();
+
+        let thread_uuid = 1 << 16;
+        let mut tracks: HashMap<u64, Track> = HashMap::new();
+        let mut names: HashMap<u64, String> = HashMap::new();
+        let mut open: HashMap<u64, Vec<(String, u64, Vec<u64>)>> = HashMap::new();
+        let mut slices = vec![];
+        let mut instants = vec![];
+        let mut counters = vec![];
+        let mut last_timestamp: HashMap<u64, u64> = HashMap::new();
+        for (i, (field, packet)) in decode(&out).into_iter().enumerate() {
+            assert_eq!(field, TRACE_PACKET);
+            let Value::Bytes(packet) = packet else { panic!("packet isn't a message") };
+            let packet = decode(packet);
+            assert_eq!(uint(&packet, PACKET_SEQUENCE_ID), Some(SEQUENCE_ID));
+            let cleared = uint(&packet, PACKET_SEQUENCE_FLAGS).unwrap() & SEQ_INCREMENTAL_STATE_CLEARED != 0;
+            assert_eq!(cleared, i == 0);
+
+            if let Some(track) = bytes(&packet, PACKET_TRACK_DESCRIPTOR) {
+                let track = decode(track);
+                let uuid = uint(&track, TRACK_UUID).unwrap();
+                if let Some(process) = bytes(&track, TRACK_PROCESS) {
+                    assert_eq!(uint(&decode(process), PROCESS_PID), Some(42));
+                }
+                let thread = bytes(&track, TRACK_THREAD).map(decode);
+                if let Some(thread) = &thread {
+                    assert_eq!(uint(thread, THREAD_PID), Some(42));
+                    assert_eq!(string(thread, THREAD_NAME).as_deref(), Some("main"));
+                }
+                let parent = uint(&track, TRACK_PARENT_UUID);
+                assert!(parent.is_none_or(|parent| tracks.contains_key(&parent)), "parent described first");
+                let described = (
+                    parent,
+                    string(&track, TRACK_NAME),
+                    thread.and_then(|thread| uint(&thread, THREAD_TID)),
+                    bytes(&track, TRACK_COUNTER).is_some(),
+                );
+                assert!(tracks.insert(uuid, described).is_none(), "track {uuid} described twice");
+            }
+
+            if let Some(interned) = bytes(&packet, PACKET_INTERNED_DATA) {
+                for (_, event_name) in decode(interned) {
+                    let Value::Bytes(event_name) = event_name else { panic!("event name isn't a message") };
+                    let event_name = decode(event_name);
+                    let iid = uint(&event_name, EVENT_NAME_IID_FIELD).unwrap();
+                    let name = string(&event_name, EVENT_NAME_NAME).unwrap();
+                    assert!(names.insert(iid, name).is_none(), "name {iid} interned twice");
+                }
+            }
+
+            if let Some(event) = bytes(&packet, PACKET_TRACK_EVENT) {
+                let event = decode(event);
+                let timestamp = uint(&packet, PACKET_TIMESTAMP).unwrap();
+                let track = uint(&event, EVENT_TRACK_UUID).unwrap();
+                assert!(tracks.contains_key(&track), "event on undescribed track {track}");
+                let last = last_timestamp.insert(track, timestamp).unwrap_or(0);
+                assert!(last <= timestamp, "track {track} goes back in time");
+                let name = uint(&event, EVENT_NAME_IID)
+                    .map(|iid| names[&iid].clone())
+                    .or_else(|| string(&event, EVENT_NAME));
+                let flows = event
+                    .iter()
+                    .filter_map(|&(field, value)| match value {
+                        Value::Fixed64(flow) if field == EVENT_FLOW_IDS => Some(flow),
+                        _ => None,
+                    })
+                    .collect();
+                match uint(&event, EVENT_TYPE).unwrap() {
+                    SLICE_BEGIN => open.entry(track).or_default().push((name.unwrap(), timestamp, flows)),
+                    SLICE_END => {
+                        let (name, begin, flows) = open.get_mut(&track).and_then(Vec::pop).expect("end without begin");
+                        slices.push((track, name, begin, timestamp, flows));
+                    }
+                    INSTANT => {
+                        let annotations: Vec<_> = event
+                            .iter()
+                            .filter(|&&(field, _)| field == EVENT_DEBUG_ANNOTATIONS)
+                            .map(|&(_, annotation)| {
+                                let Value::Bytes(annotation) = annotation else { panic!("annotation isn't a message") };
+                                let annotation = decode(annotation);
+                                (string(&annotation, ANNOTATION_NAME).unwrap(), uint(&annotation, ANNOTATION_UINT))
+                            })
+                            .collect();
+                        instants.push((track, name.unwrap(), timestamp, annotations));
+                    }
+                    COUNTER => counters.push((track, timestamp, uint(&event, EVENT_COUNTER_VALUE).unwrap())),
+                    kind => panic!("unexpected event type {kind}"),
+                }
+            }
+        }
+
+        assert!(open.values().all(Vec::is_empty), "slices left open");
+        assert_eq!(tracks[&PROCESS_UUID], (None, Some("Process 42 (rdtsc clock)".to_string()), None, false));
+        assert_eq!(tracks[&thread_uuid], (Some(PROCESS_UUID), None, Some(0), false));
+        assert_eq!(
+            tracks[&(thread_uuid + 1)],
+            (Some(thread_uuid), Some("main overlapping 1".to_string()), None, false)
+        );
+        assert_eq!(tracks[&(COUNTER_UUID | 6)], (Some(PROCESS_UUID), Some("six".to_string()), None, true));
+        assert_eq!(tracks.len(), 4);
+
+        slices.sort_by_key(|&(_, _, begin, _, _)| begin);
+        assert_eq!(
+            slices,
+            [
+                (thread_uuid, "one".to_string(), 0, 100, vec![]),
+                (thread_uuid, "two".to_string(), 10, 20, vec![77]),
+                (thread_uuid + 1, "3".to_string(), 50, 150, vec![]),
+            ]
+        );
+        let lost_args = vec![
+            ("recorded".to_string(), Some(10)),
+            ("overwritten".to_string(), Some(4)),
+            ("dropped".to_string(), Some(1)),
+        ];
+        assert_eq!(instants[0], (thread_uuid, "traces lost".to_string(), 0, lost_args));
+        let (track, name, timestamp, _) = &instants[1];
+        assert_eq!((*track, name.as_str(), *timestamp), (thread_uuid, "five", 30));
+        assert_eq!(instants.len(), 2);
+        assert_eq!(counters, [(COUNTER_UUID | 6, 40, 99)]);
+    }
+
+    #[test]
+    fn flow_at_span_boundary_goes_on_the_span_it_was_recorded_in() {
+        let tag_names = BTreeMap::from([(1, "first".to_string()), (2, "second".to_string())]);
+        let traces = [
+            Trace::new(RecordKind::FlowStart.tag_bits(), 5, 77),
+            Trace::new(1, 0, 5),
+            Trace::new(2, 5, 10),
+        ];
+        let mut out = vec![];
+        Perfetto::new(&mut out, 42, 1.0, tag_names, None).unwrap().thread(0, None, None, &traces).unwrap();
+
+        let mut names = HashMap::new();
+        let mut begins = vec![];
+        for (_, packet) in decode(&out) {
+            let Value::Bytes(packet) = packet else { panic!("packet isn't a message") };
+            let packet = decode(packet);
+            if let Some(interned) = bytes(&packet, PACKET_INTERNED_DATA) {
+                for (_, event_name) in decode(interned) {
+                    let Value::Bytes(event_name) = event_name else { panic!("event name isn't a message") };
+                    let event_name = decode(event_name);
+                    names.insert(uint(&event_name, EVENT_NAME_IID_FIELD).unwrap(), string(&event_name, EVENT_NAME_NAME).unwrap());
+                }
+            }
+            let Some(event) = bytes(&packet, PACKET_TRACK_EVENT).map(decode) else { continue };
+            if uint(&event, EVENT_TYPE) == Some(SLICE_BEGIN) {
+                let flows: Vec<u64> = event
+                    .iter()
+                    .filter_map(|&(field, value)| match value {
+                        Value::Fixed64(flow) if field == EVENT_FLOW_IDS => Some(flow),
+                        _ => None,
+                    })
+                    .collect();
+                begins.push((names[&uint(&event, EVENT_NAME_IID).unwrap()].clone(), flows));
+            }
+        }
+        assert_eq!(begins, [("first".to_string(), vec![77]), ("second".to_string(), vec![])]);
+    }
+
+    #[test]
+    fn clock_from_file_names_the_process_track() {
+        let mut file = vec![];
+        crate::write_trace_file(&mut file).unwrap();
+        let mut out = vec![];
+        write_perfetto_from(TraceReader::new(&file[..]).unwrap(), &mut out).unwrap();
+        let (_, packet) = decode(&out)[0];
+        let Value::Bytes(packet) = packet else { panic!("packet isn't a message") };
+        let track = decode(bytes(&decode(packet), PACKET_TRACK_DESCRIPTOR).unwrap());
+        assert_eq!(uint(&track, TRACK_UUID), Some(PROCESS_UUID));
+        let pid = std::process::id();
+        let name = format!("Process {pid} ({} clock)", ActiveClock::name());
+        assert_eq!(string(&track, TRACK_NAME), Some(name));
+    }
+}
@@ -0,0 +1,203 @@
+//! Streaming reader for trace files, and for bare traces from [`write_traces_binary`](crate::write_traces_binary).
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Result};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+
+use crate::file::{fields_size, Input, TraceFileHeader, FILE_MAGIC, RECORD_SIZE};
+use crate::{Trace, TraceStats};
+
+/// Reads traces one at a time, without holding a whole file in memory.
+///
+/// Accepts either a file written by [`write_trace_file`](crate::write_trace_file),
+/// or the bare traces written by [`write_traces_binary`](crate::write_traces_binary), telling them apart by the magic at the start.
+/// Empty slots (a trace whose tag, start and stop are all zero, as older versions wrote for unused parts of the array) are skipped.
+///
+/// Iterates over `Result<Trace>`, stopping after the first error.
+pub struct TraceReader<R: Read> {
+    input: Input<R>,
+    header: Option<TraceFileHeader>,
+    metadata: Vec<(String, String)>,
+    tag_names: BTreeMap<u64, String>,
+    record: Vec<u8>,
+    /// sections not yet started, only for trace files
+    threads_left: u32,
+    /// traces left in the current section, only for trace files
+    traces_left: u64,
+    thread_id: Option<u64>,
+    thread_name: Option<String>,
+    thread_stats: Option<TraceStats>,
+    tags: (Bound<u64>, Bound<u64>),
+    starts: (Bound<u64>, Bound<u64>),
+    failed: bool,
+}
+
+impl TraceReader<BufReader<File>> {
+    /// Opens a file for reading, buffered.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        TraceReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Reads the header and tag names, if it's a trace file, leaving the traces to be iterated.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = Vec::with_capacity(FILE_MAGIC.len());
+        (&mut reader)
+            .take(FILE_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        let is_trace_file = magic == FILE_MAGIC;
+        let mut input = Input::new(reader, if is_trace_file { vec![] } else { magic });
+        let (header, metadata, tag_names, threads_left) = if is_trace_file {
+            let preamble = input.preamble()?;
+            (
+                Some(preamble.header),
+                preamble.metadata,
+                preamble.tag_names,
+                preamble.thread_count,
+            )
+        } else {
+            (None, vec![], BTreeMap::new(), 0)
+        };
+        let record_size = header.map_or(RECORD_SIZE, |header| header.record_size);
+        Ok(TraceReader {
+            input,
+            header,
+            metadata,
+            tag_names,
+            threads_left,
+            record: vec![0; record_size as usize],
+            traces_left: 0,
+            thread_id: None,
+            thread_name: None,
+            thread_stats: None,
+            tags: (Bound::Unbounded, Bound::Unbounded),
+            starts: (Bound::Unbounded, Bound::Unbounded),
+            failed: false,
+        })
+    }
+
+    /// Only returns traces with a tag in `tags`, e.g. `reader.tags(5..=18)`.
+    /// Compares [`Trace::base_tag`], so instants, counters and lifetime spans with those tags are included too.
+    /// Flows have no tag of their own, so their base tag is 0.
+    pub fn tags(mut self, tags: impl RangeBounds<u64>) -> Self {
+        self.tags = (tags.start_bound().cloned(), tags.end_bound().cloned());
+        self
+    }
+
+    /// Only returns traces that start in `starts`, in ticks, e.g. `reader.starts(5000..=25000)`.
+    pub fn starts(mut self, starts: impl RangeBounds<u64>) -> Self {
+        self.starts = (starts.start_bound().cloned(), starts.end_bound().cloned());
+        self
+    }
+
+    /// For bare traces, which optional fields (e.g. [`FIELD_CPU_ID`](crate::file::FIELD_CPU_ID)) the build that wrote them recorded.
+    /// Defaults to those of this build. Ignored for trace files, whose header says.
+    pub fn bare_fields(mut self, fields: u32) -> Self {
+        if self.header.is_none() {
+            self.input.set_fields(fields);
+            self.record = vec![0; fields_size(fields) as usize];
+        }
+        self
+    }
+
+    /// The header, if this is a trace file rather than bare traces.
+    pub fn header(&self) -> Option<&TraceFileHeader> {
+        self.header.as_ref()
+    }
+
+    /// Host and CPU details from a trace file, empty for bare traces.
+    pub fn metadata(&self) -> &[(String, String)] {
+        &self.metadata
+    }
+
+    /// The metadata value for `key`, e.g. "host", if there is one.
+    pub fn metadata_value(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Process id of the process that wrote a trace file, or 1 if unknown, as the exporters label it.
+    pub(crate) fn pid(&self) -> u64 {
+        self.metadata_value("pid").and_then(|pid| pid.parse().ok()).unwrap_or(1)
+    }
+
+    /// Tag names from a trace file, empty for bare traces.
+    pub fn tag_names(&self) -> &BTreeMap<u64, String> {
+        &self.tag_names
+    }
+
+    /// Thread id of the most recently returned trace, if this is a trace file.
+    pub fn thread_id(&self) -> Option<u64> {
+        self.thread_id
+    }
+
+    /// Name of the thread of the most recently returned trace, if this is a trace file and the thread was named.
+    pub fn thread_name(&self) -> Option<&str> {
+        self.thread_name.as_deref()
+    }
+
+    /// How many traces the thread of the most recently returned trace recorded, and lost, if this is a trace file.
+    pub fn thread_stats(&self) -> Option<TraceStats> {
+        self.thread_stats
+    }
+
+    /// Reads the next trace, filtered or not. None at the end of the input.
+    fn next_trace(&mut self) -> Result<Option<Trace>> {
+        if self.header.is_none() {
+            return Ok(if self.input.read_exact_or_end(&mut self.record)? {
+                Some(self.input.trace(&self.record))
+            } else {
+                None
+            });
+        }
+        while self.traces_left == 0 {
+            if self.threads_left == 0 {
+                return Ok(None);
+            }
+            self.threads_left -= 1;
+            let section = self.input.section()?;
+            self.thread_id = Some(section.thread_id);
+            self.thread_name = section.thread_name;
+            self.thread_stats = Some(TraceStats {
+                thread_id: section.thread_id,
+                recorded: section.recorded,
+                overwritten: section.overwritten,
+                dropped: section.dropped,
+            });
+            self.traces_left = section.trace_count;
+        }
+        self.traces_left -= 1;
+        self.input.read_exact(&mut self.record)?;
+        Ok(Some(self.input.trace(&self.record)))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<Trace>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            match self.next_trace() {
+                Ok(Some(trace)) => {
+                    let empty = trace.tag == 0 && trace.start == 0 && trace.stop == 0;
+                    if !empty && self.tags.contains(&trace.base_tag()) && self.starts.contains(&trace.start) {
+                        return Some(Ok(trace));
+                    }
+                }
+                Ok(None) => return None,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
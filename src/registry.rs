@@ -0,0 +1,560 @@
+//! Per-thread trace buffers, and the process-wide registry that lets one thread read every thread's traces.
+
+use std::alloc::{alloc_zeroed, handle_alloc_error, Layout};
+use std::cell::Cell;
+use std::io::Result;
+use std::iter::{Chain, Map};
+use std::ptr::NonNull;
+use std::slice::ChunksExact;
+#[cfg(feature = "const_array")]
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed};
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "const_array")]
+use crate::CAPACITY;
+use crate::{full_policy, FullPolicy, Trace, TRACE_WORDS};
+
+/// Ring of trace words written by a single thread.
+///
+/// Only the owning thread writes, but other threads read through the registry while it does,
+/// so every word is atomic. Relaxed atomic loads and stores compile to plain moves on x86 and aarch64.
+/// A reader racing with the owner may see a partially written trace, but never undefined behavior.
+#[repr(C)]
+pub(crate) struct ThreadBuffer<W: ?Sized = [AtomicU64]> {
+    /// next word to write
+    index: AtomicUsize,
+    /// number of words that hold traces
+    len: AtomicUsize,
+    /// number of traces ever inserted, including any since overwritten
+    written: AtomicU64,
+    /// number of traces not inserted because the ring was full with [`FullPolicy::Stop`]
+    dropped: AtomicU64,
+    #[cfg(feature = "const_array")]
+    registered: AtomicBool,
+    words: W,
+}
+
+impl ThreadBuffer {
+    /// Allocates a zeroed buffer of `words` words on the heap.
+    /// Zeroed allocation lets the OS hand out pages lazily, like `Vec::with_capacity` did.
+    fn alloc(words: usize) -> NonNull<ThreadBuffer> {
+        let header = Layout::new::<ThreadBuffer<[AtomicU64; 0]>>();
+        let layout = Layout::array::<AtomicU64>(words)
+            .and_then(|array| header.extend(array))
+            .expect("trace buffer capacity overflows usize")
+            .0
+            .pad_to_align();
+        // all zero bytes is a valid ThreadBuffer: the header and words are all atomics
+        let raw = unsafe { alloc_zeroed(layout) };
+        if raw.is_null() {
+            handle_alloc_error(layout);
+        }
+        let slice = std::ptr::slice_from_raw_parts_mut(raw as *mut AtomicU64, words);
+        unsafe { NonNull::new_unchecked(slice as *mut ThreadBuffer) }
+    }
+
+    #[inline(always)]
+    pub(crate) fn insert(&self, trace: Trace) {
+        self.insert_with(trace, full_policy);
+    }
+
+    /// Like [`insert`](Self::insert), asking `policy` rather than [`full_policy`] what to do if the buffer is full.
+    #[inline(always)]
+    fn insert_with(&self, trace: Trace, policy: impl FnOnce() -> FullPolicy) {
+        let words = &self.words;
+        if words.len() < TRACE_WORDS {
+            return;
+        }
+        let mut i = self.index.load(Relaxed);
+        if i + TRACE_WORDS > words.len() {
+            match policy() {
+                FullPolicy::Wrap => i = 0,
+                FullPolicy::Stop => {
+                    self.dropped.store(self.dropped.load(Relaxed) + 1, Relaxed);
+                    return;
+                }
+                FullPolicy::Flush(flush) => {
+                    self.flush(flush);
+                    // the callback may have recorded traces of its own
+                    i = self.index.load(Relaxed);
+                    if i + TRACE_WORDS > words.len() {
+                        i = 0;
+                    }
+                }
+            }
+        }
+        for (slot, word) in words[i..i + TRACE_WORDS].iter().zip(trace.words()) {
+            slot.store(word, Relaxed);
+        }
+        i += TRACE_WORDS;
+        self.index.store(i, Relaxed);
+        if self.len.load(Relaxed) < i {
+            self.len.store(i, Relaxed);
+        }
+        self.written.store(self.written.load(Relaxed) + 1, Relaxed);
+    }
+
+    /// Forgets every trace. Only called by the owning thread, like [`insert`](Self::insert).
+    pub(crate) fn clear(&self) {
+        self.len.store(0, Relaxed);
+        self.index.store(0, Relaxed);
+        self.written.store(0, Relaxed);
+        self.dropped.store(0, Relaxed);
+    }
+
+    /// Passes every trace to `flush`, having emptied the buffer first so any it records go in the emptied buffer.
+    #[cold]
+    fn flush(&self, flush: fn(&[Trace])) {
+        let traces: Vec<Trace> = self.records().collect();
+        self.len.store(0, Relaxed);
+        self.index.store(0, Relaxed);
+        self.written.store(0, Relaxed);
+        flush(&traces);
+    }
+
+    /// Returns (traces ever inserted, traces currently held, traces dropped).
+    pub(crate) fn counts(&self) -> (u64, u64, u64) {
+        let records = self.snapshot();
+        (records.written, records.len() as u64, self.dropped())
+    }
+
+    /// Number of traces not inserted because the ring was full with [`FullPolicy::Stop`].
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Relaxed)
+    }
+
+    /// Copies the filled part of this buffer into a new heap buffer just large enough to hold it.
+    fn compact_copy(&self) -> NonNull<ThreadBuffer> {
+        let len = self.len.load(Relaxed).min(self.words.len());
+        let copy = ThreadBuffer::alloc(len);
+        let target = unsafe { copy.as_ref() };
+        for (to, from) in target.words.iter().zip(&self.words[..len]) {
+            to.store(from.load(Relaxed), Relaxed);
+        }
+        target.index.store(self.index.load(Relaxed), Relaxed);
+        target.len.store(len, Relaxed);
+        target.written.store(self.written.load(Relaxed), Relaxed);
+        target.dropped.store(self.dropped.load(Relaxed), Relaxed);
+        copy
+    }
+
+    /// Traces from oldest to newest.
+    pub(crate) fn records(&self) -> impl Iterator<Item = Trace> + '_ {
+        self.snapshot().into_iter()
+    }
+
+    /// The traces currently held, fixing which slots are included
+    /// so that the owning thread writing more can't change how many are read.
+    ///
+    /// Until the ring wraps, that's storage order.
+    /// Once it has wrapped, the oldest trace is the one at the write index, the next to be overwritten.
+    pub(crate) fn snapshot(&self) -> Records<'_> {
+        let written = self.written.load(Relaxed);
+        let len = self.len.load(Relaxed).min(self.words.len());
+        let split = if written > (len / TRACE_WORDS) as u64 {
+            self.index.load(Relaxed).min(len)
+        } else {
+            0
+        };
+        let (newer, older) = self.words[..len].split_at(split);
+        Records {
+            written,
+            older,
+            newer,
+        }
+    }
+}
+
+/// Traces held by a [`ThreadBuffer`] at one moment, see [`ThreadBuffer::snapshot`].
+#[derive(Clone, Copy)]
+pub(crate) struct Records<'a> {
+    /// number of traces ever inserted, including any since overwritten
+    pub(crate) written: u64,
+    older: &'a [AtomicU64],
+    newer: &'a [AtomicU64],
+}
+
+impl Records<'_> {
+    pub(crate) fn len(&self) -> usize {
+        (self.older.len() + self.newer.len()) / TRACE_WORDS
+    }
+}
+
+type Chunks<'a> = Chain<ChunksExact<'a, AtomicU64>, ChunksExact<'a, AtomicU64>>;
+
+impl<'a> IntoIterator for Records<'a> {
+    type Item = Trace;
+    type IntoIter = Map<Chunks<'a>, fn(&[AtomicU64]) -> Trace>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let chunks = self
+            .older
+            .chunks_exact(TRACE_WORDS)
+            .chain(self.newer.chunks_exact(TRACE_WORDS));
+        chunks.map(|chunk| bytemuck::cast(std::array::from_fn::<u64, TRACE_WORDS, _>(|i| chunk[i].load(Relaxed))))
+    }
+}
+
+/// A thread that has written at least one trace.
+pub(crate) struct Entry {
+    pub(crate) thread_id: u64,
+    pub(crate) thread_name: Option<String>,
+    /// true once the thread has exited and the registry owns `buffer`
+    pub(crate) exited: bool,
+    buffer: NonNull<ThreadBuffer>,
+}
+
+// The buffer pointer is only dereferenced while holding the registry lock.
+// The owning thread swaps its entry to a registry-owned copy under that lock before its buffer is freed.
+unsafe impl Send for Entry {}
+
+impl Entry {
+    fn buffer(&self) -> &ThreadBuffer {
+        unsafe { self.buffer.as_ref() }
+    }
+
+    fn copy(&self) -> ThreadTraces {
+        let buffer = self.buffer();
+        let records = buffer.snapshot();
+        ThreadTraces {
+            thread_id: self.thread_id,
+            thread_name: self.thread_name.clone(),
+            counts: (records.written, records.len() as u64, buffer.dropped()),
+            traces: records.into_iter().collect(),
+        }
+    }
+}
+
+/// One thread's traces, copied out of the registry so they can be written without holding its lock.
+pub(crate) struct ThreadTraces {
+    pub(crate) thread_id: u64,
+    pub(crate) thread_name: Option<String>,
+    /// as returned by [`ThreadBuffer::counts`] when `traces` were copied
+    pub(crate) counts: (u64, u64, u64),
+    /// oldest to newest
+    pub(crate) traces: Vec<Trace>,
+}
+
+static REGISTRY: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+static CAPTURE_START: OnceLock<(u64, u64)> = OnceLock::new();
+
+fn lock() -> MutexGuard<'static, Vec<Entry>> {
+    REGISTRY.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Returns (nanoseconds since the unix epoch, [`now`](crate::now)) read when the first thread registered,
+/// or now if none has yet.
+pub(crate) fn capture_start() -> (u64, u64) {
+    *CAPTURE_START.get_or_init(|| {
+        let unix_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        (unix_ns, crate::now())
+    })
+}
+
+thread_local! {
+    /// id assigned to the current thread, or 0 until it needs one
+    static THREAD_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The current thread's id, assigned the first time it's needed:
+/// when it records its first trace, or [`begin`](crate::begin)s a span with the `"thread_id"` feature.
+/// Unlike [`with_current_entry`], this doesn't allocate the thread's buffer.
+pub(crate) fn current_thread_id() -> u64 {
+    THREAD_ID.with(|id| {
+        if id.get() == 0 {
+            id.set(NEXT_THREAD_ID.fetch_add(1, Relaxed));
+        }
+        id.get()
+    })
+}
+
+/// Adds a thread's buffer to the registry, returning the id assigned to the thread.
+fn register(buffer: NonNull<ThreadBuffer>) -> u64 {
+    capture_start();
+    let thread_id = current_thread_id();
+    lock().push(Entry {
+        thread_id,
+        thread_name: std::thread::current().name().map(str::to_owned),
+        exited: false,
+        buffer,
+    });
+    thread_id
+}
+
+/// Hands an exiting thread's traces to the registry, so they're still exported after the thread is gone.
+/// Only the filled part of the buffer is kept, so short-lived threads don't pin a full capacity allocation each.
+fn retire(buffer: NonNull<ThreadBuffer>) {
+    let mut registry = lock();
+    if let Some(entry) = registry
+        .iter_mut()
+        .find(|entry| std::ptr::addr_eq(entry.buffer.as_ptr(), buffer.as_ptr()))
+    {
+        entry.buffer = unsafe { buffer.as_ref() }.compact_copy();
+        entry.exited = true;
+    }
+}
+
+/// Frees the traces of every thread that has exited.
+pub(crate) fn clear_exited() {
+    lock().retain(|entry| {
+        if entry.exited {
+            drop(unsafe { Box::from_raw(entry.buffer.as_ptr()) });
+        }
+        !entry.exited
+    });
+}
+
+/// Calls `f` with a copy of each registered thread's traces, in order of thread id.
+/// The registry lock is only held while a thread's traces are copied, not while `f` runs,
+/// so threads can exit or trace for the first time meanwhile without waiting for a slow writer.
+/// Threads that register meanwhile are included if their id is above those already copied;
+/// exited threads freed by [`clear_exited`] before they're reached are left out.
+pub(crate) fn for_each_thread(mut f: impl FnMut(ThreadTraces) -> Result<()>) -> Result<()> {
+    let mut last = 0;
+    loop {
+        let thread = lock()
+            .iter()
+            .filter(|entry| entry.thread_id > last)
+            .min_by_key(|entry| entry.thread_id)
+            .map(Entry::copy);
+        let Some(thread) = thread else {
+            return Ok(());
+        };
+        last = thread.thread_id;
+        f(thread)?;
+    }
+}
+
+/// Copies every registered thread's traces at once, for when the number of threads is needed up front.
+pub(crate) fn copy_threads() -> Vec<ThreadTraces> {
+    lock().iter().map(Entry::copy).collect()
+}
+
+/// Calls `f` with every registered thread's [`ThreadBuffer::counts`], holding the registry lock.
+pub(crate) fn for_each_count(mut f: impl FnMut(u64, (u64, u64, u64))) {
+    for entry in lock().iter() {
+        f(entry.thread_id, entry.buffer().counts());
+    }
+}
+
+#[cfg(not(feature = "const_array"))]
+struct Local {
+    thread_id: u64,
+    buffer: NonNull<ThreadBuffer>,
+}
+
+#[cfg(not(feature = "const_array"))]
+impl Local {
+    fn new() -> Self {
+        let buffer = ThreadBuffer::alloc(crate::capacity().saturating_mul(TRACE_WORDS));
+        let thread_id = register(buffer);
+        Local { thread_id, buffer }
+    }
+}
+
+#[cfg(not(feature = "const_array"))]
+impl Drop for Local {
+    fn drop(&mut self) {
+        retire(self.buffer);
+        drop(unsafe { Box::from_raw(self.buffer.as_ptr()) });
+    }
+}
+
+#[cfg(not(feature = "const_array"))]
+thread_local! {
+    static TSC_TRACE_BUFFER: Local = Local::new();
+}
+
+/// Calls `f` with the current thread's buffer, registering it on first use.
+#[cfg(not(feature = "const_array"))]
+#[inline(always)]
+pub(crate) fn with_current<R>(f: impl FnOnce(&ThreadBuffer) -> R) -> R {
+    TSC_TRACE_BUFFER.with(|local| f(unsafe { local.buffer.as_ref() }))
+}
+
+/// Like [`with_current`], also passing the thread id the registry assigned.
+#[cfg(not(feature = "const_array"))]
+pub(crate) fn with_current_entry<R>(f: impl FnOnce(u64, &ThreadBuffer) -> R) -> R {
+    TSC_TRACE_BUFFER.with(|local| f(local.thread_id, unsafe { local.buffer.as_ref() }))
+}
+
+/// Hands the thread's const array over to the registry when the thread exits.
+#[cfg(feature = "const_array")]
+struct Guard {
+    thread_id: u64,
+    buffer: NonNull<ThreadBuffer>,
+}
+
+#[cfg(feature = "const_array")]
+impl Drop for Guard {
+    fn drop(&mut self) {
+        retire(self.buffer);
+    }
+}
+
+#[cfg(feature = "const_array")]
+thread_local! {
+    static TSC_TRACE_BUFFER: ThreadBuffer<[AtomicU64; CAPACITY]> = const {
+        ThreadBuffer {
+            index: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            written: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            registered: AtomicBool::new(false),
+            words: [const { AtomicU64::new(0) }; CAPACITY],
+        }
+    };
+    static TSC_TRACE_GUARD: Guard = TSC_TRACE_BUFFER.with(|buffer| {
+        let buffer = NonNull::from(buffer as &ThreadBuffer);
+        let thread_id = register(buffer);
+        Guard { thread_id, buffer }
+    });
+}
+
+/// Calls `f` with the current thread's buffer, registering it on first use.
+#[cfg(feature = "const_array")]
+#[inline(always)]
+pub(crate) fn with_current<R>(f: impl FnOnce(&ThreadBuffer) -> R) -> R {
+    TSC_TRACE_BUFFER.with(|buffer| {
+        if !buffer.registered.load(Relaxed) {
+            // stays set after the guard is destroyed, so late traces don't try to re-register
+            buffer.registered.store(true, Relaxed);
+            TSC_TRACE_GUARD.with(|_| {});
+        }
+        f(buffer)
+    })
+}
+
+/// Like [`with_current`], also passing the thread id the registry assigned.
+#[cfg(feature = "const_array")]
+pub(crate) fn with_current_entry<R>(f: impl FnOnce(u64, &ThreadBuffer) -> R) -> R {
+    with_current(|buffer| TSC_TRACE_GUARD.with(|guard| f(guard.thread_id, buffer)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TraceStats;
+    use std::cell::RefCell;
+
+    /// A heap buffer with room for `traces` traces.
+    fn buffer(traces: usize) -> Box<ThreadBuffer> {
+        unsafe { Box::from_raw(ThreadBuffer::alloc(traces * TRACE_WORDS).as_ptr()) }
+    }
+
+    fn starts(buffer: &ThreadBuffer) -> Vec<u64> {
+        buffer.records().map(|trace| trace.start).collect()
+    }
+
+    #[test]
+    fn unwrapped_ring_is_in_storage_order() {
+        let buffer = buffer(4);
+        for start in 0..3 {
+            buffer.insert_with(Trace::new(1, start, start + 1), || FullPolicy::Wrap);
+        }
+        assert_eq!(starts(&buffer), [0, 1, 2]);
+        let stats = TraceStats::new(0, buffer.counts());
+        assert_eq!((stats.recorded, stats.overwritten, stats.dropped), (3, 0, 0));
+        assert!(!stats.wrapped());
+    }
+
+    #[test]
+    fn wrapped_ring_is_oldest_to_newest() {
+        let buffer = buffer(3);
+        for start in 0..7 {
+            buffer.insert_with(Trace::new(1, start, start + 1), || FullPolicy::Wrap);
+        }
+        // 6 overwrote 3, which overwrote 0, so the oldest left is 4, at the write index
+        assert_eq!(starts(&buffer), [4, 5, 6]);
+        let stats = TraceStats::new(0, buffer.counts());
+        assert_eq!((stats.recorded, stats.overwritten, stats.dropped), (7, 4, 0));
+        assert!(stats.wrapped());
+        assert_eq!(stats.lost(), 4);
+    }
+
+    #[test]
+    fn ring_wrapped_exactly_once_is_in_storage_order() {
+        let buffer = buffer(3);
+        for start in 0..6 {
+            buffer.insert_with(Trace::new(1, start, start + 1), || FullPolicy::Wrap);
+        }
+        assert_eq!(starts(&buffer), [3, 4, 5]);
+        assert_eq!(buffer.counts(), (6, 3, 0));
+    }
+
+    #[test]
+    fn compact_copy_keeps_order_and_counts() {
+        let buffer = buffer(3);
+        for start in 0..4 {
+            buffer.insert_with(Trace::new(1, start, start + 1), || FullPolicy::Wrap);
+        }
+        let copy = unsafe { Box::from_raw(buffer.compact_copy().as_ptr()) };
+        assert_eq!(starts(&copy), [1, 2, 3]);
+        assert_eq!(copy.counts(), buffer.counts());
+    }
+
+    #[test]
+    fn stop_keeps_the_oldest_and_counts_the_rest() {
+        let buffer = buffer(3);
+        for start in 0..5 {
+            buffer.insert_with(Trace::new(1, start, start + 1), || FullPolicy::Stop);
+        }
+        assert_eq!(starts(&buffer), [0, 1, 2]);
+        assert_eq!(buffer.counts(), (3, 3, 2));
+    }
+
+    thread_local! {
+        static FLUSHED: RefCell<Vec<Vec<u64>>> = const { RefCell::new(vec![]) };
+    }
+
+    fn flush(traces: &[Trace]) {
+        FLUSHED.with(|flushed| flushed.borrow_mut().push(traces.iter().map(|trace| trace.start).collect()));
+    }
+
+    #[test]
+    fn flush_hands_over_full_buffers_and_keeps_recording() {
+        let buffer = buffer(3);
+        for start in 0..7 {
+            buffer.insert_with(Trace::new(1, start, start + 1), || FullPolicy::Flush(flush));
+        }
+        assert_eq!(FLUSHED.with(|flushed| flushed.take()), [[0, 1, 2], [3, 4, 5]]);
+        assert_eq!(starts(&buffer), [6]);
+        assert_eq!(buffer.counts(), (1, 1, 0));
+    }
+
+    #[cfg(not(feature = "off"))]
+    #[test]
+    fn threads_can_start_and_exit_while_writing() {
+        crate::_insert_trace(1, 2, 3);
+        let mut seen = vec![];
+        for_each_thread(|thread| {
+            if seen.is_empty() {
+                // registering and retiring both take the registry lock
+                std::thread::spawn(|| crate::_insert_trace(1, 2, 3)).join().unwrap();
+            }
+            seen.push(thread.thread_id);
+            Ok(())
+        })
+        .unwrap();
+        assert!(seen.windows(2).all(|ids| ids[0] < ids[1]));
+    }
+
+    #[cfg(all(feature = "thread_id", not(feature = "off")))]
+    #[test]
+    fn begin_gets_a_thread_id_without_registering_a_buffer() {
+        let thread_id = std::thread::spawn(|| {
+            crate::begin(1).cancel();
+            current_thread_id()
+        })
+        .join()
+        .unwrap();
+        let mut registered = vec![];
+        for_each_count(|thread_id, _| registered.push(thread_id));
+        assert!(!registered.contains(&thread_id));
+    }
+}
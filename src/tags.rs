@@ -0,0 +1,79 @@
+//! Names for tags, written into trace files so tools reading them can show names rather than numbers.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, Once, PoisonError};
+
+use crate::TAG_MASK;
+
+static TAG_NAMES: Mutex<BTreeMap<u64, String>> = Mutex::new(BTreeMap::new());
+
+/// Names a tag. Replaces any name previously registered for it.
+pub fn register_tag(tag: u64, name: impl Into<String>) {
+    TAG_NAMES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(tag, name.into());
+}
+
+/// An enum of tags, whose names can be registered all at once.
+/// Derive it with `#[derive(TraceTags)]`, which names each tag after its variant,
+/// unless the variant has e.g. `#[trace_tag(name = "parse header")]`.
+pub trait TraceTags {
+    /// Each tag, with its name.
+    const TAGS: &'static [(u64, &'static str)];
+
+    /// Calls [`register_tag`] for each of [`TAGS`](TraceTags::TAGS).
+    fn register_tags() {
+        for &(tag, name) in Self::TAGS {
+            register_tag(tag, name);
+        }
+    }
+}
+
+/// Every registered tag name, ordered by tag.
+pub(crate) fn tag_names() -> BTreeMap<u64, String> {
+    TAG_NAMES.lock().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+/// A tag derived from a name, as `#[trace]` gives each function from its path, e.g. `name_tag("my_crate::parse::header")`.
+/// The same name always gives the same tag, in every version: the low 55 bits of the name's 64 bit FNV-1a hash,
+/// with bit 55 set, so it fits in [`TAG_MASK`] and doesn't collide with small hand-picked tags.
+pub const fn name_tag(name: &str) -> u64 {
+    hash_tag(fnv1a(FNV_OFFSET, name.as_bytes()))
+}
+
+/// [`name_tag`] of `"{module}::{name}"`, without building the string.
+#[cfg(feature = "tracing")]
+pub(crate) fn path_tag(module: &str, name: &str) -> u64 {
+    let hash = fnv1a(fnv1a(FNV_OFFSET, module.as_bytes()), b"::");
+    hash_tag(fnv1a(hash, name.as_bytes()))
+}
+
+/// Bit set in every tag from [`name_tag`].
+const NAME_TAG_BIT: u64 = 1 << 55;
+
+const fn hash_tag(hash: u64) -> u64 {
+    (hash & (NAME_TAG_BIT - 1)) | NAME_TAG_BIT
+}
+
+// the layout above is fixed, so it must stay within the tag bits
+const _: () = assert!(NAME_TAG_BIT << 1 == TAG_MASK + 1);
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// 64 bit FNV-1a of `bytes`, continuing from `hash`.
+const fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        i += 1;
+    }
+    hash
+}
+
+/// Must be public for use by the `#[trace]` attribute.
+/// Registers `name` for `tag` the first time it's called with each `once`.
+pub fn _register_tag_once(once: &'static Once, tag: u64, name: &str) {
+    once.call_once(|| register_tag(tag, name));
+}
@@ -12,4 +12,7 @@
 	window_height: 600, 
 	span_height: 15,
 	span_spacing: 1,
+	// ticks per nanosecond on the machine that recorded the trace, e.g. from tsc_trace::ticks_per_ns()
+	// when set, span lengths are also shown in nanoseconds
+	ticks_per_ns: null,
 }
\ No newline at end of file
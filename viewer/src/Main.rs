@@ -1,7 +1,5 @@
 pub mod config;
 
-use bytemuck::Pod;
-use bytemuck::Zeroable;
 use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
@@ -11,20 +9,31 @@ use sdl2::ttf::Font;
 use sdl2::video::WindowContext;
 use std::collections::HashMap;
 use std::env;
-use std::fs::File;
-use std::io::prelude::*;
 use std::path::Path;
 use std::thread;
 use std::time::{Duration, Instant};
+use tsc_trace::{FlowSpans, RecordKind, Trace, TraceReader, NO_ARG};
 
 const FRAME: u32 = 33_333_333;
 
-#[derive(Clone, Copy, Debug, Pod, Zeroable)]
-#[repr(C)]
-pub struct Span {
-    tag: u64,
-    start: u64,
-    stop: u64,
+/// Spans loaded from a trace file, and what the file says about them.
+pub struct TraceData {
+    spans: Vec<Trace>,
+    flows: Vec<Flow>,
+    /// from tsc_trace::trace_instant, with the kind taken out of the tag
+    instants: Vec<Trace>,
+    /// from tsc_trace::trace_counter, with the kind taken out of the tag, so stop is the value
+    counters: Vec<Trace>,
+    tag_names: HashMap<u64, String>,
+    ticks_per_ns: Option<f64>,
+}
+
+/// One step of a flow from tsc_trace::flow_start or flow_step, on the span it was recorded in.
+#[derive(Clone, Copy, Debug)]
+pub struct Flow {
+    id: u64,
+    time: u64,
+    span: Trace,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -33,7 +42,7 @@ pub struct Area {
     x_start: i32,
     y_stop: u64,
     x_stop: i32,
-    tag_data: Span,
+    tag_data: Trace,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -56,26 +65,56 @@ pub struct App {
     span_height: i32,
     /// vertical pixels between spans
     span_spacing: i32,
+    /// every loaded tag in order, each drawn in the row of its index
+    tags: Vec<u64>,
     min_start: u64,
     max_stop: u64,
     scroll: i32,
     texture_creator: TextureCreator<WindowContext>,
+    tag_names: HashMap<u64, String>,
+    ticks_per_ns: Option<f64>,
+    /// ordered by id, then time, so each step's arrow goes to the next one
+    flows: Vec<Flow>,
+    instants: Vec<Trace>,
+    /// ordered by tag, then time, so each sample's line goes to the next one
+    counters: Vec<Trace>,
+    /// smallest and largest value of each counter, which are drawn at the bottom and top of its row
+    counter_ranges: HashMap<u64, (u64, u64)>,
 }
 
 impl App {
-    pub fn new(filled_spans: &mut Vec<Span>) -> Result<App, String> {
+    pub fn new(data: &mut TraceData) -> Result<App, String> {
         let config = config::config();
-        let mut spans: Vec<Span> = vec![];
-        for span in filled_spans {
+        //names in config.js take precedence over those in the trace file
+        let mut tag_names = std::mem::take(&mut data.tag_names);
+        tag_names.extend(config.tag_names.unwrap_or_default());
+        let ticks_per_ns = config.ticks_per_ns.or(data.ticks_per_ns);
+        let mut flows = std::mem::take(&mut data.flows);
+        flows.sort_unstable_by_key(|flow| (flow.id, flow.time));
+        let instants = std::mem::take(&mut data.instants);
+        let mut counters = std::mem::take(&mut data.counters);
+        counters.sort_unstable_by_key(|counter| (counter.tag, counter.start));
+        let mut counter_ranges: HashMap<u64, (u64, u64)> = HashMap::new();
+        for counter in &counters {
+            let range = counter_ranges.entry(counter.tag).or_insert((counter.stop, counter.stop));
+            *range = (range.0.min(counter.stop), range.1.max(counter.stop));
+        }
+        let mut spans: Vec<Trace> = vec![];
+        for span in &data.spans {
             spans.push(*span);
         }
         assert!(
-            !spans.is_empty(),
+            !spans.is_empty() || !instants.is_empty() || !counters.is_empty(),
             "expected a non-empty array of trace spans"
         );
         spans.sort_unstable_by_key(|s| s.start);
-        let min_start = spans[0].start;
-        let max_stop = spans.iter().max_by_key(|s| s.stop).unwrap().stop;
+        let mut tags: Vec<u64> = spans.iter().chain(&instants).chain(&counters).map(|t| t.tag).collect();
+        tags.sort_unstable();
+        tags.dedup();
+        //instants and counter samples only have a start, their stop being unused or a value
+        let points = instants.iter().chain(&counters).map(|point| point.start);
+        let min_start = spans.iter().map(|s| s.start).chain(points.clone()).min().unwrap();
+        let max_stop = spans.iter().map(|s| s.stop).chain(points).max().unwrap();
         let draw_zones: Vec<Area> = vec![];
         let window_width = config.window_width;
         let window_height = config.window_height;
@@ -96,6 +135,9 @@ impl App {
         let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
         let texture_creator = canvas.texture_creator();
         println!("Representing {0} clock cycles in {1} pixels for {2} cycles/pixel.", max_stop - min_start, window_width, scale);
+        if let Some(ticks_per_ns) = ticks_per_ns {
+            println!("That is {0:.0} ns, {1:.1} ns/pixel.", (max_stop - min_start) as f64 / ticks_per_ns, scale as f64 / ticks_per_ns);
+        }
 
         Ok(App {
             texture_creator,
@@ -129,13 +171,72 @@ impl App {
             scale,
             span_height: config.span_height,
             span_spacing: config.span_spacing,
+            tags,
             min_start,
             max_stop,
             scroll: 0,
+            tag_names,
+            ticks_per_ns,
+            flows,
+            instants,
+            counters,
+            counter_ranges,
         })
     }
 
-    fn draw_span(&mut self, span: &Span) {
+    /// Draws each counter as a line through its samples, in the row of its tag, scaled so its range fills the row.
+    fn draw_counters(&mut self) {
+        for i in 1..self.counters.len() {
+            let (from, to) = (self.counters[i - 1], self.counters[i]);
+            if from.tag != to.tag {
+                continue;
+            }
+            let (min, max) = self.counter_ranges[&from.tag];
+            let point = |sample: &Trace| {
+                let height = ((sample.stop - min) as f64 / (max - min).max(1) as f64 * self.span_height as f64) as i32;
+                (self.x_pos(sample).saturating_sub(self.scroll), self.y_pos(sample) + self.span_height - height)
+            };
+            let (start, end) = (point(&from), point(&to));
+            self.canvas.set_draw_color(self.colors[from.tag as usize % self.colors.len()]);
+            let _ = self.canvas.draw_line(start, end);
+        }
+    }
+
+    /// Draws each instant as a tick the height of its tag's row.
+    fn draw_instants(&mut self) {
+        self.canvas.set_draw_color(Color::RGB(64, 64, 64));
+        for i in 0..self.instants.len() {
+            let x = self.x_pos(&self.instants[i]).saturating_sub(self.scroll);
+            let y = self.y_pos(&self.instants[i]);
+            let _ = self.canvas.draw_line((x, y), (x, y + self.span_height));
+        }
+    }
+
+    /// Draws an arrow from each step of a flow to the next, between the middles of the spans they're on.
+    fn draw_flows(&mut self) {
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        for i in 1..self.flows.len() {
+            let (from, to) = (self.flows[i - 1], self.flows[i]);
+            if from.id != to.id {
+                continue;
+            }
+            let point = |flow: &Flow| {
+                let x = ((flow.time - self.min_start) / (self.scale + 1)).try_into().unwrap_or(i32::MAX);
+                (x.saturating_sub(self.scroll), self.y_pos(&flow.span) + self.span_height / 2)
+            };
+            let ((x1, y1), (x2, y2)) = (point(&from), point(&to));
+            let _ = self.canvas.draw_line((x1, y1), (x2, y2));
+            //arrowhead, two short lines back from the end at 30 degrees either side of the arrow
+            let angle = ((y2 - y1) as f64).atan2((x2 - x1) as f64);
+            for side in [-0.5, 0.5] {
+                let back = angle + std::f64::consts::PI + side;
+                let end = (x2 + (8.0 * back.cos()) as i32, y2 + (8.0 * back.sin()) as i32);
+                let _ = self.canvas.draw_line((x2, y2), end);
+            }
+        }
+    }
+
+    fn draw_span(&mut self, span: &Trace) {
         let x_sz = self.x_size(span);
         let scrolled_x = self.x_pos(span).saturating_sub(self.scroll);
         //preventing drawing if span would be outside of the window
@@ -167,10 +268,22 @@ impl App {
                     (self.span_height) as u32,
                 ))
                 .unwrap_or_else(|e| panic!("draw failure {e} for span {span:?}"));
+            //outlining spans that started and stopped on different cores, since their lengths are suspect
+            if span.migrated() && x_sz > 1 {
+                self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+                self.canvas
+                    .draw_rect(Rect::new(
+                        scrolled_x,
+                        self.y_pos(span),
+                        x_sz,
+                        (self.span_height) as u32,
+                    ))
+                    .unwrap_or_else(|e| panic!("draw failure {e} for span {span:?}"));
+            }
         }
     }
 
-    fn x_size(&self, span: &Span) -> u32 {
+    fn x_size(&self, span: &Trace) -> u32 {
         ((span.stop - span.start) / (self.scale + 1))
             .try_into()
             .unwrap_or_else(|e| {
@@ -181,16 +294,47 @@ impl App {
             })
     }
 
-    fn x_pos(&self, span: &Span) -> i32 {
+    fn x_pos(&self, span: &Trace) -> i32 {
         ((span.start - self.min_start) / (self.scale + 1))
             .try_into()
             .unwrap_or_else(|_| i32::MAX)
     }
 
-    fn y_pos(&self, span: &Span) -> i32 {
-        let tag: i32 = span.tag.try_into()
-            .expect("not intended to handle very high span tag cardinality, try filtering / renumbering first: {span}");
-        ((self.span_spacing + self.span_height) * tag) + self.span_spacing
+    fn y_pos(&self, span: &Trace) -> i32 {
+        //rows go by rank among the loaded tags rather than the tag itself, so any tag fits
+        let row = self.tags.partition_point(|&tag| tag < span.tag) as i32;
+        ((self.span_spacing + self.span_height) * row) + self.span_spacing
+    }
+
+    fn label(&self, tag_data: &Trace) -> String {
+        let tag_text = self
+            .tag_names
+            .get(&tag_data.tag)
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| tag_data.tag.to_string());
+        let label = match self.ticks_per_ns {
+            Some(ticks_per_ns) => format!("{0},{1},{2:.0}ns", tag_text, (tag_data.stop - tag_data.start), (tag_data.stop - tag_data.start) as f64 / ticks_per_ns),
+            None => format!("{0},{1}", tag_text, (tag_data.stop - tag_data.start)),
+        };
+        let label = if tag_data.depth > 0 {
+            format!("{0},depth {1}", label, tag_data.depth)
+        } else {
+            label
+        };
+        let label = if tag_data.migrated() {
+            format!("{0},cpu {1}->{2}", label, tag_data.start_cpu, tag_data.stop_cpu)
+        } else {
+            label
+        };
+        let mut label = if tag_data.start_thread != tag_data.stop_thread {
+            format!("{0},thread {1}->{2}", label, tag_data.start_thread, tag_data.stop_thread)
+        } else {
+            label
+        };
+        for arg in tag_data.args.iter().filter(|&&arg| arg != NO_ARG) {
+            label = format!("{0},{1}", label, arg);
+        }
+        label
     }
 
     fn draw_text(
@@ -199,25 +343,10 @@ impl App {
         font: &sdl2::ttf::Font,
         x: i32,
         y: i32,
-        tag_data: &Span,
+        tag_text: &str,
     ) -> Result<(), String> {
-        let config = config::config();
-        let tag_text: String;
-        match config.tag_names {
-            Some(hashmap) => {
-                tag_text = hashmap
-                    .get(&tag_data.tag)
-                    .map(|s| s.to_string())
-                    .unwrap_or_else(|| tag_data.tag.to_string());
-            }
-            None => {
-                tag_text = tag_data.tag.to_string();
-            }
-        }
-        let tag_text = format!("{0},{1}", tag_text, (tag_data.stop - tag_data.start));
-
         let surface = font
-            .render(&tag_text)
+            .render(tag_text)
             .blended(Color::RGBA(0, 0, 0, 128))
             .map_err(|e| e.to_string())?;
 
@@ -234,17 +363,13 @@ impl App {
         Ok(())
     }
 
-    pub fn run(&mut self, spans: Vec<Span>, font: Font<'_, 'static>) -> Result<(), String> {
+    pub fn run(&mut self, spans: Vec<Trace>, font: Font<'_, 'static>) -> Result<(), String> {
         let mut event_pump = self.sdl_context.event_pump()?;
         let mut prev_keycount: i32 = 0;
         let mut keycount: i32 = 0;
         let mut draw_x = 0;
         let mut draw_y = 0;
-        let mut draw_data = Span {
-            tag: 0,
-            start: 0,
-            stop: 0,
-        };
+        let mut draw_data = Trace::default();
         let mut all_spans_map: HashMap<i32, i32> = HashMap::new();
         let mut most_recent_spans: Vec<Position> = vec![];
 
@@ -305,7 +430,8 @@ impl App {
                             }
                         }
                         if draw_x == 0 && draw_y == 0{
-                            println!("tag: {0:?}, position: ~{1:?}", y / (self.span_spacing + self.span_height), self.min_start as i64 + x as i64 * self.scale as i64);
+                            let row = usize::try_from(y / (self.span_spacing + self.span_height)).unwrap_or(usize::MAX);
+                            println!("tag: {0:?}, position: ~{1:?}", self.tags.get(row), self.min_start as i64 + x as i64 * self.scale as i64);
                         }
                     }
                     Event::MouseButtonUp { .. } => {
@@ -345,14 +471,18 @@ impl App {
                     }
                 }
             }
+            self.draw_counters();
+            self.draw_instants();
+            self.draw_flows();
             if (draw_x > 0) && (draw_y > 0) {
+                let label = self.label(&draw_data);
                 Self::draw_text(
                     &mut self.canvas,
                     &self.texture_creator,
                     &font,
                     draw_x,
                     draw_y,
-                    &draw_data,
+                    &label,
                 )?;
             }
 
@@ -374,75 +504,113 @@ impl App {
     }
 }
 
-pub fn load_args(mut args: Vec<String>) -> Vec<Span> {
-    let mut spans = vec![];
+pub fn load_args(mut args: Vec<String>) -> TraceData {
+    let mut data = TraceData {
+        spans: vec![],
+        flows: vec![],
+        instants: vec![],
+        counters: vec![],
+        tag_names: HashMap::new(),
+        ticks_per_ns: None,
+    };
     let config = config::config();
     match args.len() {
         1 => {panic!("Command line arguments were not provided. Format: (file path) (span range start) (span range stop) (tag range start) (tag range stop).")},
         //loading default arguments as long as the file path is provided
         2 => {
             args.push(config.default_args[0].clone());
-            spans = load_args(args);
+            data = load_args(args);
         },
         3 => {
             args.push(config.default_args[1].clone());
-            spans = load_args(args);
+            data = load_args(args);
         },
         4 => {
             args.push(config.default_args[2].clone());
-            spans = load_args(args);
+            data = load_args(args);
         },
         5 => {
             println!("One or more arguments not provided. Running with defaults for missing values.");
             args.push(config.default_args[3].clone());
-            spans = load_args(args);
+            data = load_args(args);
         },
         6 =>{
-            let mut file = File::open(&args[1]).expect("failed to open file");
-            let mut buffer = [0; 24];
             println!("Reading trace file...");
             let span_start = args[2].parse::<u64>().expect("Could not parse span range start");
             let span_stop = args[3].parse::<u64>().expect("Could not parse span range stop");
             let tag_start = args[4].parse::<u64>().expect("Could not parse tag range start");
             let tag_stop = args [5].parse::<u64>().expect("Could not parse tag range stop");
 
-            loop{
-                file.read_exact(&mut buffer).expect("failed to fill buffer");
-                let mut s: Span = bytemuck::pod_read_unaligned(&buffer);
-                if s.tag >= tag_start
-                    && s.tag <= tag_stop
-                {
-                    while s.start <= span_stop{
-                        if s.start >= span_start
-                            && s.tag >= tag_start
-                            && s.tag <= tag_stop
-                        {
-                            spans.push(s);
-                        }
-                        match file.read_exact(&mut buffer) {
-                            Ok(..) => {
-                                s = bytemuck::pod_read_unaligned(&buffer);
-                            }
-                            Err(..) => {
-                                break;
-                            }
-                        }
+            //reads both self-describing files from write_trace_file and bare traces from write_traces_binary
+            //bare files from write_traces_binary are assumed to be from a build without optional fields like cpu_id
+            //flows aren't filtered by tag, since they're drawn on the spans they were recorded in
+            let mut reader = TraceReader::open(&args[1])
+                .expect("failed to open file")
+                .bare_fields(0)
+                .starts(span_start..=span_stop);
+            data.tag_names = reader.tag_names().clone().into_iter().collect();
+            data.ticks_per_ns = reader.header().map(|header| header.ticks_per_ns);
+            let mut thread = None;
+            let mut flows = FlowSpans::new();
+            // where each of the thread's traces that's shown went in data.spans, by its position in the thread
+            let mut shown = HashMap::new();
+            let mut position = 0;
+            while let Some(trace) = reader.next() {
+                let trace = trace.expect("failed to read trace file");
+                if thread != reader.thread_id() {
+                    attach_unclaimed_flows(&mut data, &mut flows, &shown);
+                    thread = reader.thread_id();
+                    shown.clear();
+                    position = 0;
+                }
+                let claimed: Vec<Trace> = flows.push(&trace).collect();
+                position += 1;
+                match trace.kind() {
+                    RecordKind::Span if (tag_start..=tag_stop).contains(&trace.tag) => {
+                        shown.insert(position - 1, data.spans.len());
+                        data.spans.push(trace);
+                        data.flows.extend(claimed.iter().map(|flow| Flow {
+                            id: flow.stop,
+                            time: flow.start,
+                            span: trace,
+                        }));
                     }
-                    break;
+                    RecordKind::Instant if (tag_start..=tag_stop).contains(&trace.base_tag()) => {
+                        data.instants.push(Trace { tag: trace.base_tag(), ..trace })
+                    }
+                    RecordKind::Counter if (tag_start..=tag_stop).contains(&trace.base_tag()) => {
+                        data.counters.push(Trace { tag: trace.base_tag(), ..trace })
+                    }
+                    _ => {}
                 }
             }
+            attach_unclaimed_flows(&mut data, &mut flows, &shown);
         },
         _ => panic!("Command line arguments could not be parsed. Format: (file path) (span range start) (span range stop) (tag range start) (tag range stop)."),
     }
-    spans
+    data
+}
+
+/// Puts a thread's flows that no span claimed on the span that started as they were recorded, if it's shown.
+/// `shown` maps positions in the thread to indices in `data.spans`.
+fn attach_unclaimed_flows(data: &mut TraceData, flows: &mut FlowSpans, shown: &HashMap<usize, usize>) {
+    for (flow, span) in flows.finish() {
+        if let Some(&span) = span.and_then(|span| shown.get(&span)) {
+            data.flows.push(Flow {
+                id: flow.stop,
+                time: flow.start,
+                span: data.spans[span],
+            });
+        }
+    }
 }
 
 pub fn main() -> Result<(), String> {
-    let mut filled_spans = load_args(env::args().collect::<Vec<String>>());
+    let mut data = load_args(env::args().collect::<Vec<String>>());
     println!("App starting...");
-    let mut app = App::new(&mut filled_spans)?;
+    let mut app = App::new(&mut data)?;
     let ttf_context = sdl2::ttf::init().map_err(|e| e.to_string())?;
     let font_path: &Path = Path::new(&"fonts/Opensans-Regular.ttf");
     let font = ttf_context.load_font(font_path, 128)?;
-    app.run(filled_spans, font)
+    app.run(data.spans, font)
 }
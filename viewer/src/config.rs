@@ -23,4 +23,6 @@ pub struct ViewerConfig {
     pub window_height: u32,
     pub span_height: i32,
     pub span_spacing: i32,
+    /// ticks per nanosecond of the machine that recorded the trace, from `tsc_trace::ticks_per_ns()`
+    pub ticks_per_ns: Option<f64>,
 }